@@ -1,8 +1,9 @@
 // Copyright 2023 System76 <info@system76.com>
 // SPDX-License-Identifier: MPL-2.0
 
+use crate::Diagnostics;
 use compact_str::CompactString;
-use kdl::KdlNode;
+use kdl::{KdlEntry, KdlNode};
 use std::collections::BTreeMap;
 
 /// CFS configurations
@@ -38,6 +39,8 @@ pub const PROFILE_DEFAULT: Profile = Profile {
     wakeup_granularity: 1.0,
     bandwidth_size: 5,
     preempt: "voluntary",
+    util_clamp_min: None,
+    util_clamp_max: None,
 };
 
 /// Responsive CFS profile
@@ -47,6 +50,8 @@ pub const PROFILE_RESPONSIVE: Profile = Profile {
     wakeup_granularity: 0.5,
     bandwidth_size: 3,
     preempt: "full",
+    util_clamp_min: None,
+    util_clamp_max: None,
 };
 
 /// CFS Profile
@@ -61,11 +66,18 @@ pub struct Profile {
     pub bandwidth_size: u64,
     /// The type of preemption to use.
     pub preempt: &'static str,
+    /// System-wide limit of the minimum utilization clamp that tasks may request, from 0 to 1024
+    pub util_clamp_min: Option<u32>,
+    /// System-wide limit of the maximum utilization clamp that tasks may request, from 0 to 1024
+    pub util_clamp_max: Option<u32>,
 }
 
 /// Parses CFS profiles from a KDL node
-pub fn parse(nodes: &[KdlNode]) -> impl Iterator<Item = (&str, Profile)> {
-    nodes.iter().map(|node| {
+pub fn parse<'a>(
+    nodes: &'a [KdlNode],
+    diagnostics: &'a mut Diagnostics,
+) -> impl Iterator<Item = (&'a str, Profile)> + 'a {
+    nodes.iter().map(move |node| {
         let mut config = PROFILE_DEFAULT;
 
         for (name, entry) in crate::kdl::iter_properties(node) {
@@ -100,12 +112,20 @@ pub fn parse(nodes: &[KdlNode]) -> impl Iterator<Item = (&str, Profile)> {
                     }
                 }
 
+                "util-clamp-min" => {
+                    config.util_clamp_min = parse_util_clamp(entry, diagnostics);
+                }
+
+                "util-clamp-max" => {
+                    config.util_clamp_max = parse_util_clamp(entry, diagnostics);
+                }
+
                 "preempt" => {
                     if let Some(value) = entry.value().as_string() {
                         match value {
                             "voluntary" => config.preempt = "voluntary",
                             "full" => config.preempt = "full",
-                            _ => tracing::warn!("preempt expected one of: voluntary full"),
+                            _ => diagnostics.warn(entry, "preempt expected one of: voluntary full"),
                         }
                     }
                 }
@@ -117,3 +137,17 @@ pub fn parse(nodes: &[KdlNode]) -> impl Iterator<Item = (&str, Profile)> {
         (node.name().value(), config)
     })
 }
+
+fn parse_util_clamp(entry: &KdlEntry, diagnostics: &mut Diagnostics) -> Option<u32> {
+    let value = entry
+        .value()
+        .as_i64()
+        .and_then(|value| u32::try_from(value).ok())
+        .filter(|value| *value <= 1024);
+
+    if value.is_none() {
+        diagnostics.warn(entry, "util clamps expect a value between 0 and 1024");
+    }
+
+    value
+}
@@ -0,0 +1,132 @@
+// Copyright 2023 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use kdl::{KdlEntry, KdlNode};
+use std::{fmt, sync::Arc};
+
+/// Severity of a configuration diagnostic
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The configuration was accepted, but may not behave as intended
+    Warning,
+    /// Part of the configuration was rejected
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A problem found while parsing the configuration
+#[derive(Clone, Debug)]
+pub struct ConfigDiagnostic {
+    /// Whether the problem was a warning or an error
+    pub severity: Severity,
+    /// Path of the file which the problem was found in
+    pub file: Option<Arc<str>>,
+    /// Line of the file which the problem was found on
+    pub line: Option<usize>,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl fmt::Display for ConfigDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = self.file.as_deref() {
+            f.write_str(file)?;
+
+            if let Some(line) = self.line {
+                write!(f, ":{line}")?;
+            }
+
+            f.write_str(": ")?;
+        }
+
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Collects the diagnostics of the configuration files being parsed
+///
+/// Each diagnostic is also logged as it is collected.
+#[derive(Default)]
+pub struct Diagnostics {
+    file: Option<Arc<str>>,
+    line_starts: Vec<usize>,
+    list: Vec<ConfigDiagnostic>,
+}
+
+impl Diagnostics {
+    /// Attributes the diagnostics which follow to the given file.
+    pub(crate) fn enter_file(&mut self, path: &str) {
+        self.file = Some(Arc::from(path));
+        self.line_starts.clear();
+    }
+
+    /// Indexes the lines of the current file so that offsets may be mapped to line numbers.
+    pub(crate) fn index_lines(&mut self, source: &str) {
+        self.line_starts.clear();
+        self.line_starts.push(0);
+        self.line_starts
+            .extend(memchr::memchr_iter(b'\n', source.as_bytes()).map(|pos| pos + 1));
+    }
+
+    /// Reports an error at the location of a node or entry.
+    pub(crate) fn error(&mut self, at: &impl Located, message: impl fmt::Display) {
+        self.push(Severity::Error, Some(at.offset()), message.to_string());
+    }
+
+    /// Reports a warning at the location of a node or entry.
+    pub(crate) fn warn(&mut self, at: &impl Located, message: impl fmt::Display) {
+        self.push(Severity::Warning, Some(at.offset()), message.to_string());
+    }
+
+    /// Records a diagnostic, and logs it.
+    pub(crate) fn push(&mut self, severity: Severity, offset: Option<usize>, message: String) {
+        let line = offset
+            .map(|offset| self.line_starts.partition_point(|&start| start <= offset))
+            .filter(|&line| line != 0);
+
+        match (severity, line) {
+            (Severity::Warning, Some(line)) => tracing::warn!("line {line}: {message}"),
+            (Severity::Warning, None) => tracing::warn!("{message}"),
+            (Severity::Error, Some(line)) => tracing::error!("line {line}: {message}"),
+            (Severity::Error, None) => tracing::error!("{message}"),
+        }
+
+        self.list.push(ConfigDiagnostic {
+            severity,
+            file: self.file.clone(),
+            line,
+            message,
+        });
+    }
+
+    /// Takes the diagnostics which were collected.
+    #[must_use]
+    pub fn into_vec(self) -> Vec<ConfigDiagnostic> {
+        self.list
+    }
+}
+
+/// Elements of a KDL document which know their offset in the source.
+pub(crate) trait Located {
+    fn offset(&self) -> usize;
+}
+
+impl Located for KdlNode {
+    fn offset(&self) -> usize {
+        self.span().offset()
+    }
+}
+
+impl Located for KdlEntry {
+    fn offset(&self) -> usize {
+        self.span().offset()
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2023 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::scheduler::Profile;
+
+/// Settings applied while game mode is enabled
+pub struct Config {
+    /// CFS profile to apply
+    pub cfs_profile: Box<str>,
+    /// Profile which replaces the foreground profile
+    pub foreground: Option<Profile>,
+    /// CPUs to pin the foreground processes to, such as performance cores
+    pub cpus: Vec<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cfs_profile: Box::from("responsive"),
+            foreground: None,
+            cpus: Vec::new(),
+        }
+    }
+}
+
+/// Parses a CPU list, such as `0-7,16-23`
+///
+/// Returns `None` if the list is malformed, or if a range is reversed.
+#[must_use]
+pub fn parse_cpu_list(input: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for range in input.split(',') {
+        let range = range.trim();
+
+        if let Some((start, end)) = range.split_once('-') {
+            let start = start.parse::<usize>().ok()?;
+            let end = end.parse::<usize>().ok()?;
+
+            if start > end {
+                return None;
+            }
+
+            cpus.extend(start..=end);
+        } else {
+            cpus.push(range.parse::<usize>().ok()?);
+        }
+    }
+
+    Some(cpus)
+}
@@ -8,6 +8,12 @@
 /// CFS configurations
 pub mod cfs;
 
+mod diagnostic;
+pub use diagnostic::{ConfigDiagnostic, Diagnostics, Severity};
+
+/// Game mode configurations
+pub mod game_mode;
+
 pub(crate) mod kdl;
 
 mod parser;
@@ -33,30 +39,64 @@ pub struct Config {
     /// CFS profiles
     pub cfs_profiles: cfs::Config,
 
+    /// Settings applied while game mode is enabled
+    pub game_mode: game_mode::Config,
+
     /// Process scheduler config
     pub process_scheduler: scheduler::Config,
 }
 
 /// Parses the scheduler's configuration files
-pub fn config() -> Config {
-    parser::read_config()
+///
+/// Problems found while parsing are logged, and returned alongside the configuration.
+pub fn config() -> (Config, Vec<ConfigDiagnostic>) {
+    let mut diagnostics = Diagnostics::default();
+    let config = parser::read_config(&[DISTRIBUTION_PATH, SYSTEM_CONF_PATH], &mut diagnostics);
+    (config, diagnostics.into_vec())
+}
+
+/// Parses the configuration files of a directory, instead of the system paths
+///
+/// The directory is laid out like `/etc/system76-scheduler/`, with a
+/// `config.kdl` file and assignments in a `process-scheduler` directory.
+pub fn config_from(directory: &str) -> (Config, Vec<ConfigDiagnostic>) {
+    let mut diagnostics = Diagnostics::default();
+    let root = [directory.trim_end_matches('/'), "/"].concat();
+    let config = parser::read_config(&[root.as_str()], &mut diagnostics);
+    (config, diagnostics.into_vec())
 }
 
 /// Locates configuration files of a given extension from the given paths.
-pub fn configuration_files(
-    paths: &'static [&'static str],
-    extension: &'static str,
-) -> impl Iterator<Item = String> {
+///
+/// Files within each directory are yielded in lexicographic order, so that
+/// numeric prefixes such as `10-base.kdl` and `20-overrides.kdl` may be used
+/// to define the order in which they are loaded.
+pub fn configuration_files<P>(paths: P, extension: &'static str) -> impl Iterator<Item = String>
+where
+    P: IntoIterator + Send + 'static,
+    P::Item: AsRef<str>,
+{
     generator::Gn::new_scoped(move |mut scope| {
+        let mut files = Vec::new();
+
         for directory in paths {
-            if let Ok(dir) = std::fs::read_dir(directory) {
-                for entry in dir.filter_map(Result::ok) {
-                    if let Some(file_name) = entry.file_name().to_str() {
-                        if file_name.ends_with(extension) {
-                            scope.yield_([directory, "/", file_name].concat());
-                        }
-                    }
-                }
+            let directory = directory.as_ref();
+
+            let Ok(dir) = std::fs::read_dir(directory) else {
+                continue;
+            };
+
+            files.clear();
+            files.extend(
+                dir.filter_map(Result::ok)
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .filter(|file_name| file_name.ends_with(extension)),
+            );
+
+            files.sort_unstable();
+
+            for file_name in files.drain(..) {
+                scope.yield_([directory, "/", &file_name].concat());
             }
         }
 
@@ -70,3 +110,33 @@ fn read_into_string<'a>(buf: &'a mut String, path: &str) -> io::Result<&'a str>
     file.read_to_string(buf)?;
     Ok(&*buf)
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn configuration_files_sorted() {
+        let directory = std::env::temp_dir()
+            .join(format!("system76-scheduler-config-{}", std::process::id()));
+
+        std::fs::create_dir_all(&directory).unwrap();
+
+        for file in ["20-overrides.kdl", "10-base.kdl", "99-local.kdl", "notes.txt"] {
+            std::fs::write(directory.join(file), "").unwrap();
+        }
+
+        let path = directory.to_string_lossy().into_owned();
+
+        let files = super::configuration_files([path.clone()], ".kdl").collect::<Vec<_>>();
+
+        let _res = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(
+            files,
+            [
+                format!("{path}/10-base.kdl"),
+                format!("{path}/20-overrides.kdl"),
+                format!("{path}/99-local.kdl"),
+            ]
+        );
+    }
+}
@@ -1,9 +1,9 @@
-use crate::{cfs::Config, kdl::NodeExt};
+use crate::{cfs::Config, kdl::NodeExt, Diagnostics};
 use kdl::KdlNode;
 
 impl Config {
     /// Parses the CFS document node
-    pub fn read(&mut self, node: &KdlNode) {
+    pub fn read(&mut self, node: &KdlNode, diagnostics: &mut Diagnostics) {
         self.enable = node.enabled().unwrap_or(true);
 
         if !self.enable {
@@ -14,7 +14,7 @@ impl Config {
             return;
         };
 
-        for (name, profile) in crate::cfs::parse(profiles.nodes()) {
+        for (name, profile) in crate::cfs::parse(profiles.nodes(), diagnostics) {
             self.profiles.insert(name.into(), profile);
         }
     }
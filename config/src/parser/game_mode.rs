@@ -0,0 +1,46 @@
+// Copyright 2023 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{game_mode::Config, kdl::NodeExt, scheduler::Profile, Diagnostics};
+use kdl::KdlNode;
+
+impl Config {
+    /// Parses the game-mode node
+    pub fn read(&mut self, node: &KdlNode, diagnostics: &mut Diagnostics) {
+        let Some(fields) = node.children() else {
+            return;
+        };
+
+        for (name, node) in crate::kdl::fields(fields) {
+            match name {
+                "cfs-profile" => {
+                    if let Some(profile) = node.get_string(0) {
+                        self.cfs_profile = Box::from(profile);
+                    }
+                }
+
+                "foreground" => {
+                    let profile = Profile::new(Arc::from("foreground"));
+                    self.foreground = Some(profile.parse(node, &BTreeMap::new(), diagnostics));
+                }
+
+                "cpus" => {
+                    let cpus = node
+                        .get_string(0)
+                        .and_then(crate::game_mode::parse_cpu_list);
+
+                    match cpus {
+                        Some(cpus) => self.cpus = cpus,
+                        None => diagnostics.error(node, "cpus expects a CPU list, such as \"0-7\""),
+                    }
+                }
+
+                other => {
+                    diagnostics.warn(node, format_args!("unknown element: {other}"));
+                }
+            }
+        }
+    }
+}
@@ -1,18 +1,20 @@
 mod cfs;
+mod game_mode;
 mod scheduler;
 
 use std::path::Path;
 
 use crate::kdl::NodeExt;
 use crate::scheduler::ForegroundAssignments;
-use crate::{configuration_files, Config, DISTRIBUTION_PATH, SYSTEM_CONF_PATH};
+use crate::{configuration_files, Config, Diagnostics, Severity};
 use ::kdl::KdlDocument;
-use const_format::concatcp;
 
-pub fn read_config() -> Config {
+/// Reads the configuration from a list of root directories, in ascending order of precedence.
+pub fn read_config(roots: &[&str], diagnostics: &mut Diagnostics) -> Config {
     let buffer = &mut String::with_capacity(4096);
 
-    let mut config = read_assignments(read_main(buffer), buffer);
+    let config = read_main(roots, buffer, diagnostics);
+    let mut config = read_assignments(roots, config, buffer, diagnostics);
 
     let background = config
         .process_scheduler
@@ -42,43 +44,46 @@ pub fn read_config() -> Config {
     config
 }
 
-fn read_main(buffer: &mut String) -> Config {
-    const DIST_CONF: &str = concatcp!(DISTRIBUTION_PATH, "config.kdl");
-    const SYSTEM_CONF: &str = concatcp!(SYSTEM_CONF_PATH, "config.kdl");
+/// Major version of the configuration that this parser supports.
+const SCHEMA_MAJOR_VERSION: u32 = 2;
 
+fn read_main(roots: &[&str], buffer: &mut String, diagnostics: &mut Diagnostics) -> Config {
     let mut config = Config::default();
 
-    let path = if Path::new(SYSTEM_CONF).exists() {
-        SYSTEM_CONF
-    } else if Path::new(DIST_CONF).exists() {
-        DIST_CONF
-    } else {
+    // The configuration of the root with the highest precedence replaces the others.
+    let path = roots
+        .iter()
+        .rev()
+        .map(|root| [*root, "config.kdl"].concat())
+        .find(|path| Path::new(path).exists());
+
+    let Some(path) = path else {
         return config;
     };
 
+    let path = path.as_str();
+
     let span = tracing::warn_span!("parser::read_main", path);
     let _entered = span.enter();
 
+    diagnostics.enter_file(path);
+
     let Ok(buffer) = crate::read_into_string(buffer, path) else {
-        tracing::error!("failed to read file");
+        diagnostics.push(Severity::Error, None, String::from("failed to read file"));
         return config;
     };
 
+    diagnostics.index_lines(buffer);
+
     let document = match buffer.parse::<KdlDocument>() {
         Ok(document) => document,
         Err(why) => {
             let offset = why.span.offset();
-
-            let mut line_number = 1;
-
-            let mut buffer = &buffer.as_bytes()[..offset];
-
-            while let Some(pos) = memchr::memchr(b'\n', buffer) {
-                line_number += 1;
-                buffer = &buffer[pos + 1..];
-            }
-
-            tracing::error!("parsing error on line {}: {}", line_number, why);
+            diagnostics.push(
+                Severity::Error,
+                Some(offset),
+                format!("parsing error: {why}"),
+            );
             return config;
         }
     };
@@ -88,11 +93,29 @@ fn read_main(buffer: &mut String) -> Config {
             "autogroup-enabled" => {
                 config.autogroup_enabled = node.get_bool(0).unwrap_or(false);
             }
-            "cfs-profiles" => config.cfs_profiles.read(node),
-            "process-scheduler" => config.process_scheduler.read(node),
-            "version" => (),
+            "cfs-profiles" => config.cfs_profiles.read(node, diagnostics),
+            "game-mode" => config.game_mode.read(node, diagnostics),
+            "process-scheduler" => config.process_scheduler.read(node, diagnostics),
+            "version" => match node.get_string(0).and_then(major_version) {
+                Some(version) if version < SCHEMA_MAJOR_VERSION => diagnostics.warn(
+                    node,
+                    format_args!(
+                        "configuration uses major version {version}, but major version {SCHEMA_MAJOR_VERSION} is supported: \
+                        elements which were removed or renamed are ignored, see the migration notes in the README"
+                    ),
+                ),
+                Some(version) if version > SCHEMA_MAJOR_VERSION => diagnostics.warn(
+                    node,
+                    format_args!(
+                        "configuration uses major version {version}, which is newer than the supported major version {SCHEMA_MAJOR_VERSION}: \
+                        elements which this version does not know are ignored"
+                    ),
+                ),
+                Some(_) => (),
+                None => diagnostics.error(node, "version expects a string, such as \"2.0\""),
+            },
             other => {
-                tracing::warn!("unknown element: {}", other);
+                diagnostics.warn(node, format_args!("unknown element: {other}"));
             }
         }
     }
@@ -100,13 +123,27 @@ fn read_main(buffer: &mut String) -> Config {
     config
 }
 
-fn read_assignments(mut config: Config, buffer: &mut String) -> Config {
-    const PATHS: [&str; 2] = [
-        concatcp!(DISTRIBUTION_PATH, "process-scheduler/"),
-        concatcp!(SYSTEM_CONF_PATH, "process-scheduler/"),
-    ];
+/// The major version of a `version` string, such as `2` for `"2.0"`.
+fn major_version(version: &str) -> Option<u32> {
+    version.split('.').next()?.parse().ok()
+}
 
-    for path in configuration_files(&PATHS, ".kdl") {
+fn read_assignments(
+    roots: &[&str],
+    mut config: Config,
+    buffer: &mut String,
+    diagnostics: &mut Diagnostics,
+) -> Config {
+    let paths = roots
+        .iter()
+        .map(|root| [*root, "process-scheduler/"].concat())
+        .collect::<Vec<_>>();
+
+    let mut documents = Vec::new();
+
+    // Presets of every file are parsed first so that assignments may reference
+    // presets which are defined in files that are read after them.
+    for path in configuration_files(paths, ".kdl") {
         if !Path::new(&path).exists() {
             continue;
         }
@@ -117,26 +154,58 @@ fn read_assignments(mut config: Config, buffer: &mut String) -> Config {
             continue;
         };
 
+        diagnostics.enter_file(&path);
+        diagnostics.index_lines(buffer);
+
         let document = match buffer.parse::<KdlDocument>() {
             Ok(document) => document,
             Err(why) => {
-                tracing::error!("parsing error: {}", why);
+                let offset = why.span.offset();
+                diagnostics.push(
+                    Severity::Error,
+                    Some(offset),
+                    format!("parsing error: {why}"),
+                );
                 continue;
             }
         };
 
+        for node in document.nodes() {
+            if node.name().value() == "presets" {
+                config
+                    .process_scheduler
+                    .assignments
+                    .parse_presets(node, diagnostics);
+            }
+        }
+
+        documents.push((path, buffer.to_owned(), document));
+    }
+
+    for (path, source, document) in documents {
+        let span = tracing::warn_span!("parser::read_assignments", path);
+        let _entered = span.enter();
+
+        diagnostics.enter_file(&path);
+        diagnostics.index_lines(&source);
+
         for node in document.nodes() {
             match node.name().value() {
                 "assignments" => {
-                    config.process_scheduler.assignments.parse(node);
+                    config
+                        .process_scheduler
+                        .assignments
+                        .parse(node, diagnostics);
                 }
 
                 "exceptions" => {
                     config.process_scheduler.assignments.parse_exceptions(node);
                 }
 
+                "presets" => (),
+
                 other => {
-                    tracing::warn!("unknown field: {}", other);
+                    diagnostics.warn(node, format_args!("unknown field: {other}"));
                 }
             }
         }
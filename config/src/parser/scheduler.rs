@@ -1,19 +1,20 @@
 // Copyright 2023 System76 <info@system76.com>
 // SPDX-License-Identifier: MPL-2.0
 
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use crate::kdl::NodeExt;
-use crate::scheduler::{Assignments, Condition, Config, MatchCondition, Profile};
+use crate::scheduler::{Assignments, Condition, Config, MatchCondition, Profile, Threshold};
 use crate::{
     kdl::EntryExt,
-    scheduler::{IoClass, Niceness, SchedPolicy, SchedPriority},
+    scheduler::{IoClass, ManageMode, NameMatch, Niceness, SchedPolicy, SchedPriority},
+    Diagnostics,
 };
 use kdl::{KdlEntry, KdlIdentifier, KdlNode};
 
 impl Config {
     /// Parses the process-scheduler node
-    pub fn read(&mut self, node: &KdlNode) {
+    pub fn read(&mut self, node: &KdlNode, diagnostics: &mut Diagnostics) {
         self.enable = node.enabled().unwrap_or(true);
 
         if !self.enable {
@@ -21,8 +22,17 @@ impl Config {
         }
 
         if let Some(fields) = node.children() {
+            // Presets are parsed first so that assignments may reference them.
+            for (name, node) in crate::kdl::fields(fields) {
+                if name == "presets" {
+                    self.assignments.parse_presets(node, diagnostics);
+                }
+            }
+
             for (name, node) in crate::kdl::fields(fields) {
                 match name {
+                    "presets" => (),
+
                     "refresh-rate" => {
                         if let Some(value) = node.get_u16(0) {
                             self.refresh_rate = value;
@@ -35,12 +45,68 @@ impl Config {
                         }
                     }
 
-                    "assignments" => self.assignments.parse(node),
+                    "learning" => {
+                        self.learning.enable = node.enabled().unwrap_or(true);
+                        self.learning.path = node.get_string("path").map(Box::from);
+                    }
+
+                    "manage" => match node.get_string(0).map(str::parse::<ManageMode>) {
+                        Some(Ok(mode)) => self.manage = mode,
+                        _ => diagnostics.error(node, "manage expected one of: all cpu-only"),
+                    },
+
+                    "manage-active-only" => {
+                        if let Some(value) = node.get_bool(0) {
+                            self.manage_active_only = value;
+                        }
+                    }
+
+                    "name-match" => match node.get_string(0).map(str::parse::<NameMatch>) {
+                        Some(Ok(mode)) => self.name_match = mode,
+                        _ => diagnostics.error(node, "name-match expected one of: comm exe both"),
+                    },
+
+                    "interpreters" => {
+                        self.interpreters.clear();
+
+                        for entry in node.entries().iter().filter(|e| e.name().is_none()) {
+                            match entry.value().as_string() {
+                                Some(interpreter) => self.interpreters.push(Box::from(interpreter)),
+                                None => diagnostics.error(entry, "expects an interpreter name"),
+                            }
+                        }
+                    }
+
+                    "quiet-on-fullscreen" => {
+                        if let Some(value) = node.get_bool(0) {
+                            self.quiet_on_fullscreen = value;
+                        }
+                    }
+
+                    "protect-system" => {
+                        if let Some(value) = node.get_bool(0) {
+                            self.protect_system = value;
+                        }
+                    }
+
+                    "baseline-background-on-start" => {
+                        if let Some(value) = node.get_bool(0) {
+                            self.baseline_background_on_start = value;
+                        }
+                    }
+
+                    "realtime-cooldown" => {
+                        if let Some(value) = node.get_u16(0) {
+                            self.realtime_cooldown = value;
+                        }
+                    }
+
+                    "assignments" => self.assignments.parse(node, diagnostics),
 
                     "exceptions" => self.assignments.parse_exceptions(node),
 
                     other => {
-                        tracing::warn!("unknown element: {}", other);
+                        diagnostics.warn(node, format_args!("unknown element: {other}"));
                     }
                 }
             }
@@ -50,7 +116,7 @@ impl Config {
 
 impl Assignments {
     /// Parses the assignments node
-    pub fn parse(&mut self, node: &KdlNode) {
+    pub fn parse(&mut self, node: &KdlNode, diagnostics: &mut Diagnostics) {
         #[derive(PartialEq, Eq)]
         enum ParseCondition {
             Include,
@@ -74,12 +140,16 @@ impl Assignments {
                 |p| (true, p.clone()),
             );
 
-            let profile = profile.parse(profile_node);
+            let profile = profile.parse(profile_node, &self.presets, diagnostics);
 
             if !exists {
                 self.profile_insert(profile_name.clone(), profile.clone());
             }
 
+            if let Some(launcher) = profile_node.get_string("group-launcher") {
+                self.assign_by_launcher(launcher, profile.clone());
+            }
+
             if let Some(rules) = profile_node.children() {
                 for (number, pattern) in rules.nodes().iter().enumerate() {
                     let name = pattern.name().value();
@@ -87,6 +157,13 @@ impl Assignments {
                     let span = tracing::warn_span!("assignment", number = number + 1, name);
                     let _entered = span.enter();
 
+                    if let Some(entry) = pattern.get("group-launcher") {
+                        diagnostics.warn(
+                            entry,
+                            "group-launcher is only supported on profiles, and is ignored on assignments",
+                        );
+                    }
+
                     let parse_condition = match name {
                         "include" => ParseCondition::Include,
                         "exclude" => ParseCondition::Exclude,
@@ -97,15 +174,35 @@ impl Assignments {
                         ParseCondition::Include | ParseCondition::Exclude => {
                             let mut condition = Condition::default();
                             let mut profile = profile.clone();
+                            profile.parse_preset(pattern, &self.presets, diagnostics);
+
+                            for (property, entry) in crate::kdl::iter_properties(pattern) {
+                                if profile.parse_property(property, entry, diagnostics) {
+                                    continue;
+                                }
 
-                            for (property, entry) in
-                                profile.parse_properties(crate::kdl::iter_properties(pattern))
-                            {
                                 match property {
+                                    "age" => {
+                                        condition.age = entry
+                                            .value()
+                                            .as_string()
+                                            .and_then(Threshold::new_duration);
+
+                                        if condition.age.is_none() {
+                                            diagnostics.error(
+                                                entry,
+                                                "age expects a threshold, such as \"<30s\"",
+                                            );
+                                        }
+                                    }
                                     "cgroup" => {
                                         condition.cgroup =
                                             entry.value().as_string().map(MatchCondition::new);
                                     }
+                                    "container" => {
+                                        condition.container =
+                                            entry.value().as_string().map(MatchCondition::new);
+                                    }
                                     "descends" => {
                                         condition.descends =
                                             entry.value().as_string().map(MatchCondition::new);
@@ -119,16 +216,61 @@ impl Assignments {
                                             condition.parent.push(MatchCondition::new(parent));
                                         }
                                     }
+                                    "parent-pid" => {
+                                        condition.parent_pid = entry
+                                            .value()
+                                            .as_i64()
+                                            .and_then(|pid| u32::try_from(pid).ok());
+
+                                        if condition.parent_pid.is_none() {
+                                            diagnostics.error(entry, "parent-pid expects a pid");
+                                        }
+                                    }
+                                    "parent-is-session-leader" => {
+                                        condition.parent_is_session_leader =
+                                            entry.value().as_bool();
+
+                                        if condition.parent_is_session_leader.is_none() {
+                                            diagnostics.error(
+                                                entry,
+                                                "parent-is-session-leader expects true or false",
+                                            );
+                                        }
+                                    }
+                                    "snap" => {
+                                        condition.snap =
+                                            entry.value().as_string().map(MatchCondition::new);
+                                    }
+                                    "threads" => {
+                                        condition.threads =
+                                            entry.value().as_string().and_then(Threshold::new);
+
+                                        if condition.threads.is_none() {
+                                            diagnostics.error(
+                                                entry,
+                                                "threads expects a threshold, such as \">8\"",
+                                            );
+                                        }
+                                    }
                                     _ => {
-                                        tracing::error!("unknown property: {}", property);
+                                        diagnostics.error(
+                                            entry,
+                                            format_args!("unknown property: {property}"),
+                                        );
                                     }
                                 }
                             }
 
-                            let has_condition = condition.cgroup.is_some()
+                            let has_condition = condition.age.is_some()
+                                || condition.cgroup.is_some()
+                                || condition.container.is_some()
                                 || condition.descends.is_some()
                                 || condition.name.is_some()
-                                || !condition.parent.is_empty();
+                                || !condition.parent.is_empty()
+                                || condition.parent_pid.is_some()
+                                || condition.parent_is_session_leader.is_some()
+                                || condition.snap.is_some()
+                                || condition.threads.is_some();
 
                             if has_condition {
                                 self.assign_by_condition(
@@ -141,7 +283,8 @@ impl Assignments {
                         }
 
                         ParseCondition::Name => {
-                            let profile = profile.clone().parse(pattern);
+                            let profile =
+                                profile.clone().parse(pattern, &self.presets, diagnostics);
                             if name.starts_with('/') {
                                 self.assign_by_cmdline(name, profile);
                             } else {
@@ -154,6 +297,31 @@ impl Assignments {
         }
     }
 
+    /// Parses the presets node
+    pub fn parse_presets(&mut self, node: &KdlNode, diagnostics: &mut Diagnostics) {
+        let Some(document) = node.children() else {
+            return;
+        };
+
+        for preset_node in document.nodes() {
+            let preset_name = preset_node.name().value();
+
+            let span = tracing::warn_span!("Assignments::parse_presets", preset = preset_name);
+            let _entered = span.enter();
+
+            if let Some(entry) = preset_node.get("group-launcher") {
+                diagnostics.warn(
+                    entry,
+                    "group-launcher is only supported on profiles, and is ignored on presets",
+                );
+            }
+
+            let preset =
+                Profile::new(Arc::from(preset_name)).parse(preset_node, &self.presets, diagnostics);
+            self.preset_insert(preset_name, preset);
+        }
+    }
+
     /// Parses the exceptions node
     pub fn parse_exceptions(&mut self, node: &KdlNode) {
         let Some(document) = node.children() else {
@@ -199,49 +367,99 @@ impl Assignments {
 
 impl Profile {
     /// Parses a profile node
-    pub fn parse(mut self, node: &KdlNode) -> Self {
-        for (property, _) in self.parse_properties(crate::kdl::iter_properties(node)) {
-            tracing::error!("unknown property: {}", property);
+    pub fn parse(
+        mut self,
+        node: &KdlNode,
+        presets: &BTreeMap<Box<str>, Profile>,
+        diagnostics: &mut Diagnostics,
+    ) -> Self {
+        self.parse_preset(node, presets, diagnostics);
+
+        for (property, entry) in crate::kdl::iter_properties(node) {
+            if !self.parse_property(property, entry, diagnostics) {
+                diagnostics.error(entry, format_args!("unknown property: {property}"));
+            }
         }
 
         self
     }
 
-    /// Parses the properties of the profile
-    pub fn parse_properties<'a>(
-        &'a mut self,
-        entries: impl Iterator<Item = (&'a str, &'a KdlEntry)> + 'a,
-    ) -> impl Iterator<Item = (&'a str, &'a KdlEntry)> + 'a {
-        entries.filter(|&(property, entry)| {
-            match property {
-                "io" => self.parse_io(entry),
-                "nice" => self.parse_nice(entry),
-                "sched" => self.parse_sched(entry),
-                _ => return true,
-            }
+    /// Inherits the values of the preset referenced by the `preset` property
+    ///
+    /// Only values which the profile has not already set are taken from the
+    /// preset, and all of them are overridden by the properties parsed afterwards.
+    pub fn parse_preset(
+        &mut self,
+        node: &KdlNode,
+        presets: &BTreeMap<Box<str>, Profile>,
+        diagnostics: &mut Diagnostics,
+    ) {
+        let Some(preset_name) = node.get_string("preset") else {
+            return;
+        };
+
+        let Some(preset) = presets.get(preset_name) else {
+            diagnostics.error(node, format_args!("unknown preset: {preset_name}"));
+            return;
+        };
+
+        self.inherit(preset);
+    }
+
+    /// Parses a property of the profile
+    ///
+    /// Returns `false` if the property does not belong to profiles.
+    pub fn parse_property(
+        &mut self,
+        property: &str,
+        entry: &KdlEntry,
+        diagnostics: &mut Diagnostics,
+    ) -> bool {
+        match property {
+            "io" => self.parse_io(entry, diagnostics),
+            "io-latency-target-us" => self.parse_io_latency_target(entry, diagnostics),
+            "nice" => self.parse_nice(entry, diagnostics),
+            "sched" => self.parse_sched(entry, diagnostics),
+            "fallback-policy" => self.parse_fallback_policy(entry, diagnostics),
+            "rt-time-limit-us" => self.parse_rt_time_limit(entry, diagnostics),
+            "numa-node" => self.parse_numa_node(entry, diagnostics),
+            "verbose" => self.parse_verbose(entry, diagnostics),
+            "observe-only" => self.parse_observe_only(entry, diagnostics),
+            // Resolved by `Profile::parse_preset`
+            "preset" => (),
+            // Resolved by `Assignments::parse`
+            "group-launcher" => (),
+            _ => return false,
+        }
 
-            false
-        })
+        true
     }
 
     /// Parses the `io` property
     #[tracing::instrument(skip_all)]
-    pub fn parse_io(&mut self, entry: &KdlEntry) {
+    pub fn parse_io(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
+        // Derives the level from the niceness of the profile when it is applied.
+        if entry.ty().is_none() && entry.value().as_string() == Some("auto") {
+            self.io_auto = true;
+            return;
+        }
+
         let class = entry
             .ty()
             .map(KdlIdentifier::value)
             .or_else(|| entry.value().as_string());
 
         let Some(class) = class else {
-            tracing::warn!("expects class: idle best-effort realtime");
+            diagnostics.warn(entry, "expects class: auto idle best-effort realtime");
             return;
         };
 
         let Ok(class) = class.parse::<IoClass>() else {
-            tracing::error!("unknown class: {}", class);
+            diagnostics.error(entry, format_args!("unknown class: {class}"));
             return;
         };
 
+        self.io_auto = false;
         self.io = match class {
             IoClass::BestEffort => ioprio::Class::BestEffort(
                 ioprio::BePriorityLevel::from_level(entry.as_u8().unwrap_or(7))
@@ -257,28 +475,115 @@ impl Profile {
         };
     }
 
+    /// Parses the `io-latency-target-us` property
+    #[tracing::instrument(skip_all)]
+    pub fn parse_io_latency_target(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
+        let target = entry
+            .value()
+            .as_i64()
+            .and_then(|target| u32::try_from(target).ok())
+            .filter(|&target| target != 0);
+
+        let Some(target) = target else {
+            diagnostics.error(entry, "expects a latency in microseconds");
+            return;
+        };
+
+        self.io_latency_target_us = Some(target);
+    }
+
     /// Parses the `nice` property
     #[tracing::instrument(skip_all)]
-    pub fn parse_nice(&mut self, entry: &KdlEntry) {
+    pub fn parse_nice(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
         let Some(niceness) = entry.as_i8() else {
-            tracing::error!("expects number between -20 and 19");
+            diagnostics.error(entry, "expects number between -20 and 19");
             return
         };
 
         self.nice = Some(Niceness::from(niceness));
     }
 
+    /// Parses the `verbose` property
+    #[tracing::instrument(skip_all)]
+    pub fn parse_verbose(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
+        let Some(verbose) = entry.value().as_bool() else {
+            diagnostics.error(entry, "expects true or false");
+            return;
+        };
+
+        self.verbose = verbose;
+    }
+
+    /// Parses the `observe-only` property
+    #[tracing::instrument(skip_all)]
+    pub fn parse_observe_only(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
+        let Some(observe_only) = entry.value().as_bool() else {
+            diagnostics.error(entry, "expects true or false");
+            return;
+        };
+
+        self.observe_only = observe_only;
+    }
+
+    /// Parses the `rt-time-limit-us` property
+    #[tracing::instrument(skip_all)]
+    pub fn parse_rt_time_limit(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
+        let limit = entry
+            .value()
+            .as_i64()
+            .and_then(|limit| u64::try_from(limit).ok())
+            .filter(|&limit| limit != 0);
+
+        let Some(limit) = limit else {
+            diagnostics.error(entry, "expects a duration in microseconds");
+            return;
+        };
+
+        self.rt_time_limit_us = Some(limit);
+    }
+
+    /// Parses the `numa-node` property
+    #[tracing::instrument(skip_all)]
+    pub fn parse_numa_node(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
+        let Some(node) = entry
+            .value()
+            .as_i64()
+            .and_then(|node| u16::try_from(node).ok())
+        else {
+            diagnostics.error(entry, "expects a NUMA node number");
+            return;
+        };
+
+        self.numa_node = Some(node);
+    }
+
+    /// Parses the `fallback-policy` property
+    #[tracing::instrument(skip_all)]
+    pub fn parse_fallback_policy(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
+        let Some(policy) = entry.parse_to::<SchedPolicy>() else {
+            diagnostics.error(entry, "expected one of: batch idle other");
+            return;
+        };
+
+        if policy.is_realtime() {
+            diagnostics.error(entry, "fallback policy cannot be a realtime policy");
+            return;
+        }
+
+        self.sched_fallback_policy = Some(policy);
+    }
+
     /// Parses the `sched` property
     #[tracing::instrument(skip_all)]
-    pub fn parse_sched(&mut self, entry: &KdlEntry) {
+    pub fn parse_sched(&mut self, entry: &KdlEntry, diagnostics: &mut Diagnostics) {
         if let Some(policy) = entry.ty().map(KdlIdentifier::value) {
             let Ok(policy) = policy.parse::<SchedPolicy>() else {
-                tracing::error!("unknown sched policy");
+                diagnostics.error(entry, "unknown sched policy");
                 return
             };
 
             let Some(priority) = entry.as_u8() else {
-                tracing::error!("expected priority assignment between 1-99");
+                diagnostics.error(entry, "expected priority assignment between 1-99");
                 return
             };
 
@@ -289,7 +594,7 @@ impl Profile {
         }
 
         let Some(policy) = entry.parse_to::<SchedPolicy>() else {
-            tracing::error!("expected one of: batch deadline fifo idle other rr");
+            diagnostics.error(entry, "expected one of: batch deadline fifo idle other rr");
             return
         };
 
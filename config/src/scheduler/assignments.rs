@@ -11,14 +11,39 @@ use wildmatch::WildMatch;
 /// Conditional assignment
 #[derive(Default, Debug)]
 pub struct Condition {
+    /// Match by the number of seconds since the process started
+    pub age: Option<Threshold>,
     /// Match by process descendant
     pub descends: Option<MatchCondition>,
     /// Match by cgroup
     pub cgroup: Option<MatchCondition>,
+    /// Match by the name of a distrobox or toolbox container
+    pub container: Option<MatchCondition>,
     /// Match by process name
     pub name: Option<MatchCondition>,
     /// Match by process parent
     pub parent: Vec<MatchCondition>,
+    /// Match by the pid of the process parent
+    pub parent_pid: Option<u32>,
+    /// Match by whether the process parent is the leader of its session
+    pub parent_is_session_leader: Option<bool>,
+    /// Match by the name of the snap that the process belongs to
+    pub snap: Option<MatchCondition>,
+    /// Match by the number of threads of a process
+    pub threads: Option<Threshold>,
+}
+
+/// Name of the snap that a cgroup path belongs to.
+///
+/// snapd places snap apps in cgroups such as `snap.firefox.firefox-1234.scope`
+/// and snap services in `snap.name.app.service`.
+#[must_use]
+pub fn snap_name(cgroup: &str) -> Option<&str> {
+    let unit = cgroup
+        .rsplit('/')
+        .find_map(|unit| unit.strip_prefix("snap."))?;
+
+    unit.split('.').next().filter(|name| !name.is_empty())
 }
 
 /// A wildcard string match which either is or isn't
@@ -29,15 +54,39 @@ pub enum MatchCondition {
     Is(WildMatch),
     /// Is not a match for the wildcard
     IsNot(WildMatch),
+    /// Is a case-insensitive match for the lowercase wildcard
+    IsCaseless(WildMatch),
+    /// Is not a case-insensitive match for the lowercase wildcard
+    IsNotCaseless(WildMatch),
 }
 
 impl MatchCondition {
     /// Parses a `MatchCondition`
+    ///
+    /// The pattern may be prefixed with `!` to negate the match, and `i:` to
+    /// match case-insensitively, in any order, such as `!i:steam*`.
     pub fn new(input: &str) -> Self {
-        if let Some(input) = input.strip_prefix('!') {
-            Self::IsNot(WildMatch::new(input))
-        } else {
-            Self::Is(WildMatch::new(input))
+        let mut pattern = input;
+        let mut negated = false;
+        let mut caseless = false;
+
+        loop {
+            if let Some(rest) = pattern.strip_prefix('!') {
+                negated = true;
+                pattern = rest;
+            } else if let Some(rest) = pattern.strip_prefix("i:") {
+                caseless = true;
+                pattern = rest;
+            } else {
+                break;
+            }
+        }
+
+        match (negated, caseless) {
+            (false, false) => Self::Is(WildMatch::new(pattern)),
+            (true, false) => Self::IsNot(WildMatch::new(pattern)),
+            (false, true) => Self::IsCaseless(WildMatch::new(&pattern.to_lowercase())),
+            (true, true) => Self::IsNotCaseless(WildMatch::new(&pattern.to_lowercase())),
         }
     }
 
@@ -47,6 +96,88 @@ impl MatchCondition {
         match self {
             Self::Is(condition) => condition.matches(input),
             Self::IsNot(condition) => !condition.matches(input),
+            Self::IsCaseless(condition) => condition.matches(&input.to_lowercase()),
+            Self::IsNotCaseless(condition) => !condition.matches(&input.to_lowercase()),
+        }
+    }
+}
+
+/// A numeric comparison against a threshold, such as `>8` or `<=4`
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Threshold {
+    /// Value is less than the threshold
+    Less(u64),
+    /// Value is less than or equal to the threshold
+    LessOrEqual(u64),
+    /// Value is equal to the threshold
+    Equal(u64),
+    /// Value is greater than or equal to the threshold
+    GreaterOrEqual(u64),
+    /// Value is greater than the threshold
+    Greater(u64),
+}
+
+impl Threshold {
+    /// Parses a `Threshold` from a comparison operator followed by a number
+    #[must_use]
+    pub fn new(input: &str) -> Option<Self> {
+        Self::parse_with(input, |value| value.parse::<u64>().ok())
+    }
+
+    /// Parses a `Threshold` of seconds from a comparison operator followed by a duration
+    ///
+    /// The duration may have a suffix of `s`, `m`, `h`, or `d`, such as `<30s`.
+    /// Without a suffix, the duration is in seconds.
+    #[must_use]
+    pub fn new_duration(input: &str) -> Option<Self> {
+        Self::parse_with(input, |value| {
+            let (value, unit) = match value.char_indices().next_back()? {
+                (pos, 's') => (&value[..pos], 1),
+                (pos, 'm') => (&value[..pos], 60),
+                (pos, 'h') => (&value[..pos], 60 * 60),
+                (pos, 'd') => (&value[..pos], 24 * 60 * 60),
+                _ => (value, 1),
+            };
+
+            value.trim().parse::<u64>().ok()?.checked_mul(unit)
+        })
+    }
+
+    /// Parses a `Threshold` from a comparison operator followed by a value
+    /// that is converted to a number by `parse_value`
+    ///
+    /// The operator may be one of `<`, `<=`, `=`, `>=`, or `>`. Without an
+    /// operator, the value must be equal to the threshold.
+    pub fn parse_with(input: &str, parse_value: impl FnOnce(&str) -> Option<u64>) -> Option<Self> {
+        let input = input.trim();
+
+        let (constructor, value): (fn(u64) -> Self, &str) =
+            if let Some(value) = input.strip_prefix("<=") {
+                (Self::LessOrEqual, value)
+            } else if let Some(value) = input.strip_prefix(">=") {
+                (Self::GreaterOrEqual, value)
+            } else if let Some(value) = input.strip_prefix('<') {
+                (Self::Less, value)
+            } else if let Some(value) = input.strip_prefix('>') {
+                (Self::Greater, value)
+            } else if let Some(value) = input.strip_prefix('=') {
+                (Self::Equal, value)
+            } else {
+                (Self::Equal, input)
+            };
+
+        parse_value(value.trim()).map(constructor)
+    }
+
+    /// Identifies if the value is within the threshold
+    #[must_use]
+    pub fn matches(self, value: u64) -> bool {
+        match self {
+            Self::Less(threshold) => value < threshold,
+            Self::LessOrEqual(threshold) => value <= threshold,
+            Self::Equal(threshold) => value == threshold,
+            Self::GreaterOrEqual(threshold) => value >= threshold,
+            Self::Greater(threshold) => value > threshold,
         }
     }
 }
@@ -65,10 +196,14 @@ pub struct Assignments {
     pub exceptions_conditions: Vec<Condition>,
     /// Assignment profiles
     pub(crate) profiles: BTreeMap<Arc<str>, Profile>,
+    /// Presets which profiles may inherit from
+    pub(crate) presets: BTreeMap<Box<str>, Profile>,
     /// Profiles mapped by name
     pub(crate) profile_by_name: BTreeMap<Box<str>, Profile>,
     /// Profiles mapped by cmdline
     pub(crate) profile_by_cmdline: BTreeMap<Box<str>, Profile>,
+    /// Profiles of the process trees of launchers, mapped by the launcher's name
+    pub(crate) profile_by_launcher: BTreeMap<Box<str>, Profile>,
 }
 
 impl Assignments {
@@ -76,8 +211,10 @@ impl Assignments {
     pub fn clear(&mut self) {
         self.conditions.clear();
         self.profiles.clear();
+        self.presets.clear();
         self.profile_by_name.clear();
         self.profile_by_cmdline.clear();
+        self.profile_by_launcher.clear();
         self.exceptions_by_cmdline.clear();
         self.exceptions_by_name.clear();
         self.exceptions_conditions.clear();
@@ -95,6 +232,18 @@ impl Assignments {
         self.profile_by_cmdline.get(process)
     }
 
+    /// Get the group profile of a launcher by its name
+    #[must_use]
+    pub fn get_by_launcher<'a>(&'a self, launcher: &str) -> Option<&'a Profile> {
+        self.profile_by_launcher.get(launcher)
+    }
+
+    /// Check if any launchers are grouped
+    #[must_use]
+    pub fn has_launchers(&self) -> bool {
+        !self.profile_by_launcher.is_empty()
+    }
+
     /// Check if a process is excepted by its name
     #[must_use]
     pub fn is_exception_by_name(&self, name: &str) -> bool {
@@ -113,6 +262,22 @@ impl Assignments {
         self.profiles.get(profile)
     }
 
+    /// Get a preset by its name
+    #[must_use]
+    pub fn preset<'a>(&'a self, preset: &str) -> Option<&'a Profile> {
+        self.presets.get(preset)
+    }
+
+    /// Insert a new preset
+    pub fn preset_insert(&mut self, name: &str, profile: Profile) {
+        self.presets.insert(name.into(), profile);
+    }
+
+    /// Iterates all assignment profiles
+    pub fn profiles(&self) -> impl Iterator<Item = &Profile> {
+        self.profiles.values()
+    }
+
     /// Insert a new profile
     pub fn profile_insert(&mut self, name: Arc<str>, profile: Profile) {
         self.profiles.insert(name, profile);
@@ -123,6 +288,11 @@ impl Assignments {
         self.profile_by_name.insert(name.into(), profile);
     }
 
+    /// Assign the process tree of a launcher to a profile by the launcher's name
+    pub fn assign_by_launcher(&mut self, name: &str, profile: Profile) {
+        self.profile_by_launcher.insert(name.into(), profile);
+    }
+
     /// Assign a condition to a profile
     pub fn assign_by_condition(
         &mut self,
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: MPL-2.0
 
 mod assignments;
-pub use assignments::{Assignments, Condition, MatchCondition};
+pub use assignments::{snap_name, Assignments, Condition, MatchCondition, Threshold};
 
 mod profile;
 pub use profile::Profile;
@@ -17,6 +17,24 @@ pub struct Config {
     pub execsnoop: bool,
     /// Defines the refresh rate for polling processes
     pub refresh_rate: u16,
+    /// Defines which process priorities are managed
+    pub manage: ManageMode,
+    /// Skips reapplying priorities to processes which were idle since the last refresh
+    pub manage_active_only: bool,
+    /// Defines which identity of a process that name rules are matched against
+    pub name_match: NameMatch,
+    /// Interpreters whose processes are named by the script that they run
+    pub interpreters: Vec<Box<str>>,
+    /// Pauses process refreshes while the foreground process is fullscreen
+    pub quiet_on_fullscreen: bool,
+    /// Excepts the init system and critical daemons which are not explicitly assigned
+    pub protect_system: bool,
+    /// Applies the background profile to assignable processes from the first refresh
+    pub baseline_background_on_start: bool,
+    /// Seconds to watch for instability after a realtime policy is applied, where 0 disables it
+    pub realtime_cooldown: u16,
+    /// Records processes which have no assignment
+    pub learning: Learning,
     /// Process profile assignments
     pub assignments: Assignments,
     /// Foreground profiles
@@ -31,6 +49,15 @@ impl Default for Config {
             enable: false,
             execsnoop: false,
             refresh_rate: 60,
+            manage: ManageMode::default(),
+            manage_active_only: false,
+            name_match: NameMatch::default(),
+            interpreters: Vec::new(),
+            quiet_on_fullscreen: false,
+            protect_system: true,
+            baseline_background_on_start: false,
+            realtime_cooldown: 0,
+            learning: Learning::default(),
             assignments: Assignments::default(),
             foreground: None,
             pipewire: None,
@@ -46,6 +73,66 @@ pub struct ForegroundAssignments {
     pub foreground: Profile,
 }
 
+/// Records processes which have no assignment, to aid in writing assignments
+#[derive(Clone, Debug, Default)]
+pub struct Learning {
+    /// Enables the learning mode
+    pub enable: bool,
+    /// File to write unmatched processes to, else they are logged
+    pub path: Option<Box<str>>,
+}
+
+/// Defines which process priorities are managed by the scheduler
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ManageMode {
+    /// Niceness, I/O priority, and scheduler policy
+    #[default]
+    All,
+    /// Only niceness
+    CpuOnly,
+}
+
+impl FromStr for ManageMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mode = match s {
+            "all" => ManageMode::All,
+            "cpu-only" => ManageMode::CpuOnly,
+            _ => return Err(()),
+        };
+
+        Ok(mode)
+    }
+}
+
+/// Defines which identity of a process that name rules are matched against
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NameMatch {
+    /// Only the `comm` of the process, which the process may change itself
+    Comm,
+    /// Only the basename of the executable
+    #[default]
+    Exe,
+    /// The basename of the executable, if the `comm` of the process agrees with it
+    Both,
+}
+
+impl FromStr for NameMatch {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mode = match s {
+            "comm" => NameMatch::Comm,
+            "exe" => NameMatch::Exe,
+            "both" => NameMatch::Both,
+            _ => return Err(()),
+        };
+
+        Ok(mode)
+    }
+}
+
 /// I/O Class
 #[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum IoClass {
@@ -15,10 +15,24 @@ pub struct Profile {
     pub nice: Option<Niceness>,
     /// I/O priority class
     pub io: ioprio::Class,
+    /// Derives a best-effort I/O priority from the niceness instead of using `io`
+    pub io_auto: bool,
+    /// Target I/O latency of the process's cgroup, in microseconds
+    pub io_latency_target_us: Option<u32>,
     /// Scheduler policy for a process
     pub sched_policy: SchedPolicy,
     /// Scheduler policy priority
     pub sched_priority: SchedPriority,
+    /// Non-realtime policy to apply if a realtime policy could not be assigned
+    pub sched_fallback_policy: Option<SchedPolicy>,
+    /// CPU time that a realtime process may consume without blocking, in microseconds
+    pub rt_time_limit_us: Option<u64>,
+    /// NUMA node whose CPUs the process is restricted to
+    pub numa_node: Option<u16>,
+    /// Logs each application of the profile at the info level
+    pub verbose: bool,
+    /// Matches processes without changing their priorities
+    pub observe_only: bool,
 }
 
 impl Profile {
@@ -28,8 +42,76 @@ impl Profile {
             name,
             nice: None,
             io: ioprio::Class::BestEffort(ioprio::BePriorityLevel::lowest()),
+            io_auto: false,
+            io_latency_target_us: None,
             sched_policy: SchedPolicy::Other,
             sched_priority: SchedPriority(1),
+            sched_fallback_policy: None,
+            rt_time_limit_us: None,
+            numa_node: None,
+            verbose: false,
+            observe_only: false,
         }
     }
+
+    /// Takes the values of a preset for each property that is still at its default
+    pub fn inherit(&mut self, preset: &Profile) {
+        let default = Profile::new(self.name.clone());
+
+        if self.nice.is_none() {
+            self.nice = preset.nice;
+        }
+
+        if self.io == default.io && !self.io_auto {
+            self.io = preset.io;
+            self.io_auto = preset.io_auto;
+        }
+
+        if self.io_latency_target_us.is_none() {
+            self.io_latency_target_us = preset.io_latency_target_us;
+        }
+
+        if self.sched_policy == default.sched_policy
+            && self.sched_priority == default.sched_priority
+        {
+            self.sched_policy = preset.sched_policy;
+            self.sched_priority = preset.sched_priority;
+        }
+
+        if self.sched_fallback_policy.is_none() {
+            self.sched_fallback_policy = preset.sched_fallback_policy;
+        }
+
+        if self.rt_time_limit_us.is_none() {
+            self.rt_time_limit_us = preset.rt_time_limit_us;
+        }
+
+        if self.numa_node.is_none() {
+            self.numa_node = preset.numa_node;
+        }
+
+        self.verbose |= preset.verbose;
+        self.observe_only |= preset.observe_only;
+    }
+
+    /// The I/O priority class to assign, resolving `io="auto"` from the niceness
+    ///
+    /// Automatic levels follow the kernel's own mapping of niceness to a
+    /// best-effort level, where -20 maps to 0 and 19 maps to 7.
+    #[must_use]
+    pub fn io_class(&self) -> ioprio::Class {
+        if !self.io_auto {
+            return self.io;
+        }
+
+        let nice = self.nice.map_or(0, Niceness::get);
+
+        #[allow(clippy::cast_sign_loss)]
+        let level = ((nice + 20) / 5) as u8;
+
+        ioprio::Class::BestEffort(
+            ioprio::BePriorityLevel::from_level(level)
+                .unwrap_or_else(ioprio::BePriorityLevel::lowest),
+        )
+    }
 }
@@ -4,28 +4,87 @@
 pub mod paths;
 
 use crate::config::cfs::Profile;
-use paths::{SchedPaths, BANDWIDTH_SIZE_PATH};
+use paths::{SchedPaths, BANDWIDTH_SIZE_PATH, UTIL_CLAMP_MAX_PATH, UTIL_CLAMP_MIN_PATH};
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::io::Write;
+use std::path::Path;
 use std::{fs, io};
+use zvariant::Type;
+
+/// Values written to the kernel by a CFS profile.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Type)]
+pub struct Tweaks {
+    pub latency: u64,
+    pub min_gran: u64,
+    pub wakeup_gran: u64,
+    pub bandwidth_size: u64,
+    /// Empty if the kernel does not support changing the preemption mode.
+    pub preempt: String,
+}
 
 /// Apply a configuration to CPU scheduler latencies.
 #[allow(clippy::cast_precision_loss)]
 #[allow(clippy::cast_sign_loss)]
 #[allow(clippy::cast_possible_truncation)]
-pub fn tweak(paths: &SchedPaths, conf: &Profile) {
+pub fn tweak(paths: &SchedPaths, conf: &Profile) -> Tweaks {
     let modifier = latency_modifier(num_cpus::get() as f64);
 
+    let latency = modifier * conf.latency;
     let min_gran = (modifier as f64 * conf.latency as f64 / conf.nr_latency as f64) as u64;
     let wakeup_gran = (modifier as f64 * conf.wakeup_granularity) as u64;
+    let bandwidth_size = conf.bandwidth_size * 1000;
 
-    write_value(paths.latency, modifier * conf.latency);
+    write_value(paths.latency, latency);
     write_value(paths.min_gran, min_gran);
     write_value(paths.wakeup_gran, wakeup_gran);
-    write_value(BANDWIDTH_SIZE_PATH, conf.bandwidth_size * 1000);
+    write_value(BANDWIDTH_SIZE_PATH, bandwidth_size);
+
+    let mut preempt = String::new();
 
     if let Some(preempt_path) = paths.preempt {
         write_value(preempt_path, conf.preempt);
+        preempt.push_str(conf.preempt);
+    }
+
+    write_util_clamp(conf.util_clamp_min, conf.util_clamp_max);
+
+    Tweaks {
+        latency,
+        min_gran,
+        wakeup_gran,
+        bandwidth_size,
+        preempt,
+    }
+}
+
+/// Apply the system-wide utilization clamp limits, if the kernel supports them.
+fn write_util_clamp(min: Option<u32>, max: Option<u32>) {
+    if !Path::new(UTIL_CLAMP_MIN_PATH).exists() {
+        return;
+    }
+
+    // The kernel refuses a minimum that exceeds the maximum, so the maximum
+    // is raised before the minimum is written, and lowered after it.
+    let current_max = fs::read_to_string(UTIL_CLAMP_MAX_PATH)
+        .ok()
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .unwrap_or(1024);
+
+    let raise_max = max.map_or(false, |max| max >= current_max);
+
+    if raise_max {
+        write_value(UTIL_CLAMP_MAX_PATH, max.unwrap_or(1024));
+    }
+
+    if let Some(min) = min {
+        write_value(UTIL_CLAMP_MIN_PATH, min);
+    }
+
+    if !raise_max {
+        if let Some(max) = max {
+            write_value(UTIL_CLAMP_MAX_PATH, max);
+        }
     }
 }
 
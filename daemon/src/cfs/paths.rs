@@ -5,6 +5,8 @@ use std::path::Path;
 
 pub const BANDWIDTH_SIZE_PATH: &str = "/proc/sys/kernel/sched_cfs_bandwidth_slice_us";
 pub const PREEMPT_PATH: &str = "/sys/kernel/debug/sched/preempt";
+pub const UTIL_CLAMP_MIN_PATH: &str = "/proc/sys/kernel/sched_util_clamp_min";
+pub const UTIL_CLAMP_MAX_PATH: &str = "/proc/sys/kernel/sched_util_clamp_max";
 
 #[derive(Debug, thiserror::Error)]
 pub enum SchedPathsError {
@@ -1,9 +1,14 @@
 // Copyright 2021-2022 System76 <info@system76.com>
 // SPDX-License-Identifier: MPL-2.0
 
-use crate::Event;
+use crate::{
+    cfs::Tweaks,
+    config::scheduler::{IoClass, SchedPolicy},
+    Event,
+};
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use tokio::sync::mpsc::Sender;
+use std::{str::FromStr, time::Duration};
+use tokio::sync::{mpsc::Sender, oneshot};
 use zvariant::{OwnedValue, Type, Value};
 
 #[derive(
@@ -27,9 +32,31 @@ pub enum CpuMode {
     Responsive = 3,
 }
 
+/// Sources of foreground process changes, in ascending order of priority.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ForegroundSource {
+    Script,
+    Compositor,
+}
+
+impl FromStr for ForegroundSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = match s {
+            "script" => Self::Script,
+            "compositor" => Self::Compositor,
+            _ => return Err(()),
+        };
+
+        Ok(source)
+    }
+}
+
 pub(crate) struct Server {
     pub cpu_mode: CpuMode,
     pub cpu_profile: String,
+    pub game_mode: bool,
     pub tx: Sender<Event>,
 }
 
@@ -45,14 +72,43 @@ pub trait Client {
     #[dbus_proxy(property)]
     fn cpu_profile(&self) -> zbus::fdo::Result<String>;
 
+    #[dbus_proxy(property)]
+    fn game_mode(&self) -> zbus::fdo::Result<bool>;
+
+    /// Applies a CFS profile, and returns the values that were written
+    fn apply_cpu_profile(&self, profile: &str) -> zbus::fdo::Result<Tweaks>;
+
+    /// Applies a CFS profile for a number of seconds, and returns the values that were written
+    fn apply_cpu_profile_for(&self, profile: &str, seconds: u32) -> zbus::fdo::Result<Tweaks>;
+
+    /// Whether the daemon is permitted to assign a niceness, I/O class, and scheduler policy
+    fn can_apply(&self, nice: i32, io_class: &str, policy: &str) -> zbus::fdo::Result<bool>;
+
     fn reload_configuration(&self) -> zbus::fdo::Result<()>;
 
+    /// Loads the configuration from a directory instead of the system paths, and returns its diagnostics
+    fn reload_configuration_from(&self, directory: &str) -> zbus::fdo::Result<Vec<String>>;
+
     fn set_cpu_mode(&mut self, cpu_mode: CpuMode) -> zbus::fdo::Result<()>;
 
     fn set_cpu_profile(&mut self, profile: &str) -> zbus::fdo::Result<()>;
 
     /// This process will have its process group prioritized over background processes
     fn set_foreground_process(&mut self, pid: u32) -> zbus::fdo::Result<()>;
+
+    /// Enables or disables the settings of the `game-mode` configuration
+    fn set_game_mode(&mut self, enable: bool) -> zbus::fdo::Result<()>;
+
+    /// Sets the foreground process on behalf of a source, where the source of the highest priority wins
+    fn set_foreground_process_from(&mut self, source: &str, pid: u32) -> zbus::fdo::Result<()>;
+
+    /// Sets the foreground process on behalf of a source, along with whether it is fullscreen
+    fn set_foreground_process_fullscreen(
+        &mut self,
+        source: &str,
+        pid: u32,
+        fullscreen: bool,
+    ) -> zbus::fdo::Result<()>;
 }
 
 #[dbus_interface(name = "com.system76.Scheduler")]
@@ -67,10 +123,114 @@ impl Server {
         &self.cpu_profile
     }
 
+    #[dbus_interface(property)]
+    fn game_mode(&self) -> bool {
+        self.game_mode
+    }
+
+    /// Applies a CFS profile, and returns the values that were written
+    ///
+    /// Unlike `set_cpu_profile`, unknown profiles are rejected with an error.
+    async fn apply_cpu_profile(&self, profile: String) -> zbus::fdo::Result<Tweaks> {
+        self.apply_cpu_profile_with(profile, None).await
+    }
+
+    /// Applies a CFS profile for a number of seconds, and returns the values that were written
+    ///
+    /// The previous CPU mode and profile are restored once the duration expires,
+    /// unless the CPU mode or profile is changed in the meantime.
+    async fn apply_cpu_profile_for(
+        &self,
+        profile: String,
+        seconds: u32,
+    ) -> zbus::fdo::Result<Tweaks> {
+        if seconds == 0 {
+            return Err(zbus::fdo::Error::InvalidArgs(String::from(
+                "duration must be at least one second",
+            )));
+        }
+
+        let duration = Duration::from_secs(u64::from(seconds));
+        self.apply_cpu_profile_with(profile, Some(duration)).await
+    }
+
+    /// Whether the daemon is permitted to assign a niceness, I/O class, and scheduler policy
+    ///
+    /// Nothing is assigned, so that clients may disable options which would fail.
+    /// I/O classes are `idle`, `best-effort`, and `realtime`, and policies are
+    /// `batch`, `fifo`, `idle`, `other`, and `rr`.
+    fn can_apply(&self, nice: i32, io_class: String, policy: String) -> zbus::fdo::Result<bool> {
+        let nice = i8::try_from(nice)
+            .ok()
+            .filter(|nice| (-20..=19).contains(nice))
+            .ok_or_else(|| {
+                zbus::fdo::Error::InvalidArgs(format!("niceness out of range: {nice}"))
+            })?;
+
+        let Ok(io_class) = io_class.parse::<IoClass>() else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "unknown I/O class: {io_class}"
+            )));
+        };
+
+        let Ok(policy) = policy.parse::<SchedPolicy>() else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "unknown scheduler policy: {policy}"
+            )));
+        };
+
+        Ok(crate::priority::can_apply(nice, io_class, policy))
+    }
+
     async fn reload_configuration(&self) {
         let _res = self.tx.send(Event::ReloadConfiguration).await;
     }
 
+    /// Loads the configuration from a directory instead of the system paths, and returns its diagnostics
+    ///
+    /// The directory is laid out like `/etc/system76-scheduler`. This is intended
+    /// for test harnesses and staged rollouts, and is restricted to root.
+    async fn reload_configuration_from(
+        &self,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
+        directory: String,
+    ) -> zbus::fdo::Result<Vec<String>> {
+        let Some(sender) = header.sender()? else {
+            return Err(zbus::fdo::Error::AccessDenied(String::from(
+                "the caller could not be identified",
+            )));
+        };
+
+        let uid = zbus::fdo::DBusProxy::new(connection)
+            .await?
+            .get_connection_unix_user(sender.clone().into())
+            .await?;
+
+        if uid != 0 {
+            return Err(zbus::fdo::Error::AccessDenied(String::from(
+                "only root may load configurations from other paths",
+            )));
+        }
+
+        if !std::path::Path::new(&directory).is_dir() {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "not a directory: {directory}"
+            )));
+        }
+
+        let (reply, response) = oneshot::channel();
+
+        let _res = self
+            .tx
+            .send(Event::ReloadConfigurationFrom(directory, reply))
+            .await;
+
+        response
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed(String::from("daemon stopped")))
+    }
+
     async fn set_cpu_mode(&mut self, cpu_mode: CpuMode) {
         self.cpu_mode = cpu_mode;
 
@@ -94,7 +254,80 @@ impl Server {
 
     /// This process will have its process group prioritized over background processes
     async fn set_foreground_process(&mut self, pid: u32) {
-        let _res = self.tx.send(Event::SetForegroundProcess(pid)).await;
+        let _res = self
+            .tx
+            .send(Event::SetForegroundProcess(
+                ForegroundSource::Compositor,
+                pid,
+                false,
+            ))
+            .await;
+    }
+
+    /// Enables or disables the settings of the `game-mode` configuration
+    ///
+    /// While enabled, the game mode CFS profile is applied, and the foreground
+    /// process may be given a more aggressive profile and pinned to a set of CPUs.
+    /// The previous CPU mode and profile are restored once it is disabled.
+    /// The `GameMode` property changes once the settings have been applied.
+    async fn set_game_mode(&self, enable: bool) {
+        let _res = self.tx.send(Event::SetGameMode(enable)).await;
+    }
+
+    /// Sets the foreground process on behalf of a source, where the source of the highest priority wins
+    ///
+    /// Sources are `compositor` and `script`, in descending order of priority.
+    /// A pid of `0` unregisters the source.
+    async fn set_foreground_process_from(
+        &mut self,
+        source: String,
+        pid: u32,
+    ) -> zbus::fdo::Result<()> {
+        self.set_foreground_process_fullscreen(source, pid, false)
+            .await
+    }
+
+    /// Sets the foreground process on behalf of a source, along with whether it is fullscreen
+    ///
+    /// If `quiet-on-fullscreen` is enabled, process refreshes are paused while
+    /// the foreground process is fullscreen.
+    async fn set_foreground_process_fullscreen(
+        &mut self,
+        source: String,
+        pid: u32,
+        fullscreen: bool,
+    ) -> zbus::fdo::Result<()> {
+        let Ok(source) = source.parse::<ForegroundSource>() else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "unknown foreground source: {source}"
+            )));
+        };
+
+        let _res = self
+            .tx
+            .send(Event::SetForegroundProcess(source, pid, fullscreen))
+            .await;
+
+        Ok(())
+    }
+}
+
+impl Server {
+    async fn apply_cpu_profile_with(
+        &self,
+        profile: String,
+        duration: Option<Duration>,
+    ) -> zbus::fdo::Result<Tweaks> {
+        let (reply, response) = oneshot::channel();
+
+        let _res = self
+            .tx
+            .send(Event::ApplyCpuProfile(profile, duration, reply))
+            .await;
+
+        response
+            .await
+            .unwrap_or_else(|_| Err(zbus::fdo::Error::Failed(String::from("daemon stopped"))))
     }
 }
 
@@ -116,3 +349,153 @@ pub(crate) async fn interface_handle(
 
     Some(iface_handle)
 }
+
+/// Updates the `GameMode` property, and notifies clients of the change.
+pub(crate) async fn game_mode_changed(handle: &zbus::InterfaceRef<Server>, enable: bool) {
+    let mut interface = handle.get_mut().await;
+    interface.game_mode = enable;
+
+    if let Err(why) = interface.game_mode_changed(handle.signal_context()).await {
+        tracing::error!("failed to signal the game mode change: {why}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientProxy, CpuMode, ForegroundSource, Server};
+    use crate::Event;
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+
+    /// A private bus which is stopped when dropped.
+    struct Bus(Child);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _res = self.0.kill();
+            let _res = self.0.wait();
+        }
+    }
+
+    /// Launches a private `dbus-daemon`, returning its address.
+    fn bus() -> Option<(Bus, String)> {
+        let mut child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .arg("--address=unix:tmpdir=/tmp")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+
+        let mut address = String::new();
+        let stdout = child.stdout.take()?;
+        let bus = Bus(child);
+
+        BufReader::new(stdout).read_line(&mut address).ok()?;
+
+        Some((bus, address.trim().to_owned()))
+    }
+
+    #[tokio::test]
+    #[ignore = "requires dbus-daemon, run with `cargo test -- --ignored`"]
+    async fn client_drives_server() {
+        let (_bus, address) = bus().expect("failed to launch dbus-daemon");
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+
+        let server = zbus::ConnectionBuilder::address(address.as_str())
+            .unwrap()
+            .name("com.system76.Scheduler")
+            .unwrap()
+            .serve_at(
+                "/com/system76/Scheduler",
+                Server {
+                    cpu_mode: CpuMode::Auto,
+                    cpu_profile: String::from("auto"),
+                    game_mode: false,
+                    tx,
+                },
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let connection = zbus::ConnectionBuilder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let mut client = ClientProxy::builder(&connection)
+            .cache_properties(zbus::CacheProperties::No)
+            .build()
+            .await
+            .unwrap();
+
+        client.set_cpu_mode(CpuMode::Responsive).await.unwrap();
+        let event = rx.recv().await;
+        assert!(matches!(event, Some(Event::SetCpuMode)), "{event:?}");
+        assert_eq!(CpuMode::Responsive, client.cpu_mode().await.unwrap());
+
+        client.set_cpu_profile("custom").await.unwrap();
+        let event = rx.recv().await;
+        assert!(matches!(event, Some(Event::SetCustomCpuMode)), "{event:?}");
+        assert_eq!(CpuMode::Custom, client.cpu_mode().await.unwrap());
+        assert_eq!("custom", client.cpu_profile().await.unwrap());
+
+        client.set_foreground_process(42).await.unwrap();
+        let event = rx.recv().await;
+        assert!(
+            matches!(
+                event,
+                Some(Event::SetForegroundProcess(
+                    ForegroundSource::Compositor,
+                    42,
+                    false
+                ))
+            ),
+            "{event:?}"
+        );
+
+        client
+            .set_foreground_process_fullscreen("script", 7, true)
+            .await
+            .unwrap();
+        let event = rx.recv().await;
+        assert!(
+            matches!(
+                event,
+                Some(Event::SetForegroundProcess(
+                    ForegroundSource::Script,
+                    7,
+                    true
+                ))
+            ),
+            "{event:?}"
+        );
+
+        assert!(client
+            .set_foreground_process_from("unknown", 7)
+            .await
+            .is_err());
+
+        client.reload_configuration().await.unwrap();
+        let event = rx.recv().await;
+        assert!(
+            matches!(event, Some(Event::ReloadConfiguration)),
+            "{event:?}"
+        );
+
+        client.set_game_mode(true).await.unwrap();
+        let event = rx.recv().await;
+        assert!(matches!(event, Some(Event::SetGameMode(true))), "{event:?}");
+        assert!(!client.game_mode().await.unwrap());
+
+        let handle = super::interface_handle(&server).await.unwrap();
+        super::game_mode_changed(&handle, true).await;
+        assert!(client.game_mode().await.unwrap());
+    }
+}
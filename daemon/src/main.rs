@@ -19,10 +19,14 @@ mod process;
 mod pw;
 mod service;
 mod utils;
+#[cfg(feature = "wayland")]
+mod wayland;
 
+use anyhow::Context;
 use clap::ArgMatches;
 use dbus::{CpuMode, Server};
 use std::{
+    io::Write,
     path::Path,
     time::{Duration, Instant},
 };
@@ -34,17 +38,27 @@ use crate::utils::Buffer;
 
 #[derive(Debug)]
 enum Event {
+    ApplyCpuProfile(
+        String,
+        Option<Duration>,
+        tokio::sync::oneshot::Sender<zbus::fdo::Result<cfs::Tweaks>>,
+    ),
     ExecCreate(ExecCreate),
+    ExecStart(ExecCreate),
     OnBattery(bool),
     Pipewire(scheduler_pipewire::ProcessEvent),
+    RealtimeWatchdog,
     RefreshProcessMap,
     ReloadConfiguration,
+    ReloadConfigurationFrom(String, tokio::sync::oneshot::Sender<Vec<String>>),
+    RevertCpuProfile(u64),
     SetCpuMode,
     SetCustomCpuMode,
-    SetForegroundProcess(u32),
+    SetForegroundProcess(dbus::ForegroundSource, u32, bool),
+    SetGameMode(bool),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct ExecCreate {
     pid: u32,
     parent_pid: u32,
@@ -79,8 +93,6 @@ fn main() -> anyhow::Result<()> {
                     .with_target(false)
                     .init();
 
-                let connection = Connection::system().await?;
-
                 let matches = clap::command!()
                     .propagate_version(true)
                     .subcommand_required(true)
@@ -90,23 +102,51 @@ fn main() -> anyhow::Result<()> {
                             .about("select a CFS scheduler profile")
                             .arg(clap::arg!([PROFILE])),
                     )
+                    .subcommand(
+                        clap::Command::new("config")
+                            .about("manage configuration files")
+                            .subcommand_required(true)
+                            .subcommand(
+                                clap::Command::new("init")
+                                    .about("write the default configuration files to a directory")
+                                    .arg(clap::arg!([DIR] "defaults to /etc/system76-scheduler")),
+                            ),
+                    )
                     .subcommand(
                         clap::Command::new("daemon")
                             .about("launch the system daemon")
+                            .arg(clap::arg!(--once "apply the configuration once, and exit"))
                             .subcommand(
                                 clap::Command::new("reload").about("reload system configuration"),
                             ),
                     )
+                    .subcommand(
+                        clap::Command::new("explain")
+                            .about("report which running processes each profile currently matches"),
+                    )
                     .subcommand(
                         clap::Command::new("pipewire")
                             .about("monitor pipewire process ID activities"),
-                    )
-                    .get_matches();
+                    );
 
+                #[cfg(feature = "wayland")]
+                let matches = matches.subcommand(clap::Command::new("wayland").about(
+                    "set the focused window of a wlroots compositor as the foreground process",
+                ));
+
+                let matches = matches.get_matches();
+
+                // Only the subcommands which use DBus connect to the system bus.
                 match matches.subcommand() {
-                    Some(("cpu", matches)) => cpu(connection, matches).await,
-                    Some(("daemon", matches)) => daemon(connection, matches, owner).await,
+                    Some(("config", matches)) => config_files(matches),
+                    Some(("cpu", matches)) => cpu(Connection::system().await?, matches).await,
+                    Some(("daemon", matches)) => {
+                        daemon(Connection::system().await?, matches, owner).await
+                    }
+                    Some(("explain", _matches)) => explain(owner),
                     Some(("pipewire", _matches)) => pw::main().await,
+                    #[cfg(feature = "wayland")]
+                    Some(("wayland", _matches)) => wayland::main(Connection::system().await?).await,
                     _ => Ok(()),
                 }
             };
@@ -148,6 +188,117 @@ async fn cpu(connection: Connection, args: &ArgMatches) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn config_files(args: &ArgMatches) -> anyhow::Result<()> {
+    match args.subcommand() {
+        Some(("init", matches)) => config_init(matches),
+        _ => Ok(()),
+    }
+}
+
+/// Writes the default configuration files to a directory, so that they may be edited.
+///
+/// Files which already exist are left untouched.
+fn config_init(args: &ArgMatches) -> anyhow::Result<()> {
+    const FILES: [(&str, &str); 2] = [
+        ("config.kdl", include_str!("../../data/config.kdl")),
+        (
+            "process-scheduler/pop_os.kdl",
+            include_str!("../../data/pop_os.kdl"),
+        ),
+    ];
+
+    let dir = args
+        .get_one::<String>("DIR")
+        .map_or("/etc/system76-scheduler", String::as_str);
+
+    for (file, contents) in FILES {
+        let path = Path::new(dir).join(file);
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+
+        let result = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path);
+
+        match result {
+            Ok(mut file) => {
+                file.write_all(contents.as_bytes())
+                    .with_context(|| format!("failed to write {}", path.display()))?;
+
+                println!("created {}", path.display());
+            }
+
+            Err(why) if why.kind() == std::io::ErrorKind::AlreadyExists => {
+                println!("skipped {}: already exists", path.display());
+            }
+
+            Err(why) => {
+                return Err(why).with_context(|| format!("failed to create {}", path.display()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the profiles assigned to running processes, without applying them.
+///
+/// Pipewire and foreground assignments are made at runtime by the daemon,
+/// so processes without a configured assignment are reported as unassigned. The
+/// running processes are read from `/proc`, so no DBus connection is needed.
+fn explain(owner: LCellOwner<'_>) -> anyhow::Result<()> {
+    let mut buffer = Buffer::new();
+
+    let service = &mut service::Service::new(owner);
+    service.reload_configuration();
+
+    if !service.config.process_scheduler.enable {
+        println!("process scheduler is disabled");
+        return Ok(());
+    }
+
+    for (profile, processes) in service.process_map_explain(&mut buffer) {
+        println!("{profile}: {} processes", processes.len());
+
+        for (pid, cmdline) in processes {
+            println!("    {pid} {cmdline}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the CFS profile and the process priorities once, without registering on the bus.
+///
+/// Foreground and pipewire processes are not tracked, so only the configured
+/// assignments and the default profiles are applied.
+async fn daemon_once(
+    connection: &Connection,
+    service: &mut service::Service<'_>,
+    buffer: &mut Buffer,
+) -> anyhow::Result<()> {
+    autogroup_set(service.config.autogroup_enabled);
+
+    if service.config.cfs_profiles.enable {
+        let on_battery = match UPowerProxy::new(connection).await {
+            Ok(upower) => upower.on_battery().await.unwrap_or(false),
+            Err(_) => false,
+        };
+
+        service.cfs_on_battery(on_battery);
+    }
+
+    if service.config.process_scheduler.enable {
+        service.process_map_refresh(buffer);
+    }
+
+    Ok(())
+}
+
 #[allow(clippy::too_many_lines)]
 async fn daemon(
     connection: Connection,
@@ -163,6 +314,10 @@ async fn daemon(
     let service = &mut service::Service::new(owner);
     service.reload_configuration();
 
+    if args.get_flag("once") {
+        return daemon_once(&connection, service, &mut buffer).await;
+    }
+
     let (tx, mut rx) = tokio::sync::mpsc::channel(4);
 
     let upower = UPowerProxy::new(&connection).await?;
@@ -189,8 +344,18 @@ async fn daemon(
                 Duration::from_secs(u64::from(service.config.process_scheduler.refresh_rate));
             let tx = tx.clone();
             async move {
-                let _res = tx.send(Event::RefreshProcessMap).await;
-                tokio::time::sleep(refresh_rate).await;
+                loop {
+                    if tx.send(Event::RefreshProcessMap).await.is_err() {
+                        break;
+                    }
+
+                    // A refresh rate of 0 only scans processes at startup.
+                    if refresh_rate.is_zero() {
+                        break;
+                    }
+
+                    tokio::time::sleep(refresh_rate).await;
+                }
             }
         });
 
@@ -210,6 +375,11 @@ async fn daemon(
         if service.config.process_scheduler.pipewire.is_some() {
             tokio::task::spawn_local(pw::monitor(tx.clone()));
         }
+
+        // Reverts realtime policies which destabilize the system during their cooldown.
+        if service.config.process_scheduler.realtime_cooldown != 0 {
+            tokio::task::spawn_local(realtime_watchdog(tx.clone()));
+        }
     }
 
     connection
@@ -219,6 +389,7 @@ async fn daemon(
             Server {
                 cpu_mode: CpuMode::Auto,
                 cpu_profile: String::from("auto"),
+                game_mode: false,
                 tx: tx.clone(),
             },
         )
@@ -235,24 +406,80 @@ async fn daemon(
 
     while let Some(event) = rx.recv().await {
         match event {
+            Event::ApplyCpuProfile(name, duration, reply) => {
+                let result = cfs_apply_by_name(service, &upower, &name).await;
+
+                let cpu_mode = result.as_ref().ok().map(|&(cpu_mode, _)| cpu_mode);
+                let _res = reply.send(result.map(|(_, tweaks)| tweaks));
+
+                let Some(cpu_mode) = cpu_mode else {
+                    continue;
+                };
+
+                tracing::debug!("applied {name} config");
+
+                let Some(handle) = dbus::interface_handle(&connection).await else {
+                    break;
+                };
+
+                let mut interface = handle.get_mut().await;
+
+                if let Some(duration) = duration {
+                    let generation =
+                        service.cfs_revert_schedule(interface.cpu_mode, &interface.cpu_profile);
+
+                    tokio::task::spawn_local({
+                        let tx = tx.clone();
+                        async move {
+                            tokio::time::sleep(duration).await;
+                            let _res = tx.send(Event::RevertCpuProfile(generation)).await;
+                        }
+                    });
+                } else {
+                    service.cfs_revert_cancel();
+                }
+
+                interface.cpu_mode = cpu_mode;
+                interface.cpu_profile = name;
+            }
+
+            Event::ExecStart(ExecCreate {
+                pid,
+                parent_pid,
+                name,
+                cmdline,
+            }) => {
+                service.assign_provisional(&mut buffer, pid, parent_pid, name, cmdline);
+            }
+
             Event::ExecCreate(ExecCreate {
                 pid,
                 parent_pid,
                 name,
                 cmdline,
             }) => {
-                service.assign_new_process(&mut buffer, pid, parent_pid, name, cmdline);
-                service.assign_children(&mut buffer, pid);
+                if service.assign_new_process(&mut buffer, pid, parent_pid, name, cmdline) {
+                    service.assign_children(&mut buffer, pid);
+                }
+
                 service.garbage_clean(&mut buffer);
             }
 
+            Event::RealtimeWatchdog => {
+                service.realtime_watchdog(&mut buffer);
+            }
+
             Event::RefreshProcessMap => {
-                service.process_map_refresh(&mut buffer);
+                if !service.refresh_paused() {
+                    service.process_map_refresh(&mut buffer);
+                }
             }
 
-            Event::SetForegroundProcess(pid) => {
-                tracing::debug!("setting {pid} as foreground process");
-                service.set_foreground_process(&mut buffer, pid);
+            Event::SetForegroundProcess(source, pid, fullscreen) => {
+                tracing::debug!(
+                    "setting {pid} as foreground process of {source:?} (fullscreen: {fullscreen})"
+                );
+                service.set_foreground_source(&mut buffer, source, pid, fullscreen);
                 service.garbage_clean(&mut buffer);
             }
 
@@ -277,7 +504,89 @@ async fn daemon(
                 }
             }
 
+            Event::RevertCpuProfile(generation) => {
+                let Some((cpu_mode, profile)) = service.cfs_revert_take(generation) else {
+                    continue;
+                };
+
+                let name = cpu_profile_name(cpu_mode, &profile);
+
+                if let Err(why) = cfs_apply_by_name(service, &upower, name).await {
+                    tracing::error!("failed to revert to the {name} CPU profile: {why}");
+                    continue;
+                }
+
+                tracing::info!("reverted to the {name} CPU profile");
+
+                let Some(handle) = dbus::interface_handle(&connection).await else {
+                    break;
+                };
+
+                let mut interface = handle.get_mut().await;
+                interface.cpu_mode = cpu_mode;
+                interface.cpu_profile = profile;
+            }
+
+            Event::SetGameMode(enable) => {
+                if enable == service.game_mode_active() {
+                    continue;
+                }
+
+                let Some(handle) = dbus::interface_handle(&connection).await else {
+                    break;
+                };
+
+                if enable {
+                    let (cpu_mode, cpu_profile) = {
+                        let interface = handle.get().await;
+                        (interface.cpu_mode, interface.cpu_profile.clone())
+                    };
+
+                    let name = String::from(&*service.config.game_mode.cfs_profile);
+
+                    match cfs_apply_by_name(service, &upower, &name).await {
+                        Ok((cpu_mode, _)) => {
+                            let mut interface = handle.get_mut().await;
+                            interface.cpu_mode = cpu_mode;
+                            interface.cpu_profile = name;
+                        }
+
+                        Err(why) => {
+                            tracing::error!(
+                                "game mode failed to apply the {name} CPU profile: {why}"
+                            );
+                            continue;
+                        }
+                    }
+
+                    service.cfs_revert_cancel();
+                    service.game_mode_enable(&mut buffer, cpu_mode, cpu_profile);
+                    dbus::game_mode_changed(&handle, true).await;
+                    tracing::info!("game mode enabled");
+                } else {
+                    let Some((cpu_mode, profile)) = service.game_mode_disable(&mut buffer) else {
+                        continue;
+                    };
+
+                    dbus::game_mode_changed(&handle, false).await;
+
+                    let name = cpu_profile_name(cpu_mode, &profile);
+
+                    if let Err(why) = cfs_apply_by_name(service, &upower, name).await {
+                        tracing::error!("failed to restore the {name} CPU profile: {why}");
+                    } else {
+                        let mut interface = handle.get_mut().await;
+                        interface.cpu_mode = cpu_mode;
+                        interface.cpu_profile = profile;
+                    }
+
+                    tracing::info!("game mode disabled");
+                }
+            }
+
             Event::SetCpuMode => {
+                service.cfs_revert_cancel();
+
                 let Some(handle) = dbus::interface_handle(&connection).await else {
                     break;
                 };
@@ -305,6 +614,8 @@ async fn daemon(
             }
 
             Event::SetCustomCpuMode => {
+                service.cfs_revert_cancel();
+
                 let Some(handle) = dbus::interface_handle(&connection).await else {
                     break;
                 };
@@ -322,11 +633,60 @@ async fn daemon(
                 service.reload_configuration();
                 autogroup_set(service.config.autogroup_enabled);
             }
+
+            Event::ReloadConfigurationFrom(directory, reply) => {
+                tracing::info!("reloading configuration from {directory}");
+                let diagnostics = service.reload_configuration_from(&directory);
+                autogroup_set(service.config.autogroup_enabled);
+
+                let _res = reply.send(diagnostics.iter().map(ToString::to_string).collect());
+            }
         }
     }
     Ok(())
 }
 
+/// Name of the CFS profile that a CPU mode applies, where custom modes apply the given profile.
+fn cpu_profile_name(cpu_mode: CpuMode, profile: &str) -> &str {
+    match cpu_mode {
+        CpuMode::Auto => "auto",
+        CpuMode::Default => "default",
+        CpuMode::Responsive => "responsive",
+        CpuMode::Custom => profile,
+    }
+}
+
+/// Applies a CFS profile by name, returning the CPU mode that it represents and the values written.
+async fn cfs_apply_by_name(
+    service: &service::Service<'_>,
+    upower: &UPowerProxy<'_>,
+    name: &str,
+) -> zbus::fdo::Result<(CpuMode, cfs::Tweaks)> {
+    let (cpu_mode, profile) = match name {
+        "auto" => {
+            let on_battery = upower.on_battery().await.unwrap_or(false);
+            (CpuMode::Auto, Some(service.cfs_battery_config(on_battery)))
+        }
+        "default" => (CpuMode::Default, Some(service.cfs_default_config())),
+        "responsive" => (CpuMode::Responsive, Some(service.cfs_responsive_config())),
+        name => (CpuMode::Custom, service.cfs_config(name)),
+    };
+
+    let Some(profile) = profile else {
+        return Err(zbus::fdo::Error::InvalidArgs(format!(
+            "unknown CPU profile: {name}"
+        )));
+    };
+
+    let Some(tweaks) = service.cfs_apply(profile) else {
+        return Err(zbus::fdo::Error::NotSupported(String::from(
+            "CFS profiles are disabled or unsupported",
+        )));
+    };
+
+    Ok((cpu_mode, tweaks))
+}
+
 async fn battery_monitor(mut events: PropertyStream<'_, bool>, tx: Sender<Event>) {
     use futures::StreamExt;
 
@@ -343,10 +703,25 @@ fn autogroup_set(enable: bool) {
     let _res = std::fs::write(PATH, if enable { b"1" } else { b"0" });
 }
 
+/// Ticks the realtime watchdog every second.
+async fn realtime_watchdog(tx: Sender<Event>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        if tx.send(Event::RealtimeWatchdog).await.is_err() {
+            break;
+        }
+    }
+}
+
 /// Listens to exec events from the kernel to get process IDs in realtime.
 fn integrate_execsnoop(tx: Sender<Event>) {
     tracing::info!("monitoring process IDs in realtime with execsnoop");
     let (scheduled_tx, mut scheduled_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (started_tx, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
     std::thread::spawn(move || {
         match execsnoop::watch() {
             Ok(mut watcher) => {
@@ -364,15 +739,17 @@ fn integrate_execsnoop(tx: Sender<Event>) {
                         process.pid,
                         process.parent_pid
                     );
-                    let _res = scheduled_tx.send((
-                        Instant::now() + Duration::from_secs(2),
-                        ExecCreate {
-                            pid: process.pid,
-                            parent_pid: process.parent_pid,
-                            name: name.to_owned(),
-                            cmdline: cmdline.to_owned(),
-                        },
-                    ));
+                    let exec = ExecCreate {
+                        pid: process.pid,
+                        parent_pid: process.parent_pid,
+                        name: name.to_owned(),
+                        cmdline: cmdline.to_owned(),
+                    };
+
+                    // Rules which do not depend on the cgroup may be applied immediately.
+                    let _res = started_tx.send(exec.clone());
+
+                    let _res = scheduled_tx.send((Instant::now() + Duration::from_secs(2), exec));
                 }
             }
             Err(error) => {
@@ -381,6 +758,13 @@ fn integrate_execsnoop(tx: Sender<Event>) {
         }
     });
 
+    let started_events = tx.clone();
+    tokio::task::spawn_local(async move {
+        while let Some(process) = started_rx.recv().await {
+            let _res = started_events.send(Event::ExecStart(process)).await;
+        }
+    });
+
     tokio::task::spawn_local(async move {
         while let Some((delay, process)) = scheduled_rx.recv().await {
             tokio::time::sleep_until(delay.into()).await;
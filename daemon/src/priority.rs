@@ -1,49 +1,506 @@
 // Copyright 2022 System76 <info@system76.com>
 // SPDX-License-Identifier: MPL-2.0
 
-use std::os::unix::prelude::OsStrExt;
+use std::{
+    collections::BTreeMap,
+    io,
+    os::unix::prelude::OsStrExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
 
 use concat_in_place::strcat;
 use ioprio::{Pid, Target};
-use system76_scheduler_config::scheduler::{Profile, SchedPolicy, SchedPriority};
+use system76_scheduler_config::scheduler::{
+    IoClass, ManageMode, Profile, SchedPolicy, SchedPriority,
+};
 
 use crate::utils::Buffer;
 
+/// Number of priority syscalls issued, which is reported by process refreshes.
+static SYSCALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of priority syscalls issued since the daemon started.
+pub fn syscalls() -> usize {
+    SYSCALLS.load(Ordering::Relaxed)
+}
+
+/// The `io.latency` target that was last written to each cgroup.
+static IO_LATENCY: Mutex<BTreeMap<Box<str>, u32>> = Mutex::new(BTreeMap::new());
+
+fn count_syscall() {
+    SYSCALLS.fetch_add(1, Ordering::Relaxed);
+}
+
 /// Get the priority of a process.
 // pub fn get(pid: u32) -> i32 {
 //     unsafe { libc::getpriority(libc::PRIO_PROCESS, pid) }
 // }
 
-pub fn set(buffer: &mut Buffer, process: u32, profile: &Profile) {
+pub fn set(buffer: &mut Buffer, process: u32, profile: &Profile, mode: ManageMode) {
     buffer.path.clear();
     let tasks = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(process) "/task");
 
+    // The process may have exited since it was last seen.
     let Ok(tasks) = std::fs::read_dir(tasks) else {
         return;
     };
 
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        log_change(buffer, process, profile, mode);
+    }
+
+    // Tasks may exit while they are being iterated, so a task that fails
+    // should not prevent the remaining tasks from being assigned.
     for task in tasks.filter_map(Result::ok) {
         let Some(process) = atoi::atoi::<u32>(task.file_name().as_bytes()) else {
-            return;
+            continue;
         };
 
         if let Some(nice) = profile.nice {
+            count_syscall();
             unsafe {
                 libc::setpriority(libc::PRIO_PROCESS, process, libc::c_int::from(nice.get()));
             }
         }
 
-        set_policy(process, profile.sched_policy, profile.sched_priority);
+        if ManageMode::CpuOnly == mode {
+            continue;
+        }
+
+        if let Err(why) = set_policy(process, profile.sched_policy, profile.sched_priority) {
+            // The task has already exited.
+            if why.raw_os_error() == Some(libc::ESRCH) {
+                continue;
+            }
+
+            // Only refusals of realtime policies are worth a warning, since other
+            // failures recur on every refresh for processes which cannot be changed.
+            if profile.sched_policy.is_realtime() {
+                tracing::warn!(
+                    pid = process,
+                    policy = ?profile.sched_policy,
+                    rt_priority = profile.sched_priority.get(),
+                    source = %profile.name,
+                    error = %why,
+                    "failed to set scheduler policy"
+                );
+            } else {
+                tracing::debug!(
+                    pid = process,
+                    policy = ?profile.sched_policy,
+                    source = %profile.name,
+                    error = %why,
+                    "failed to set scheduler policy"
+                );
+            }
+
+            // Realtime policies may be refused when the realtime budget is exhausted.
+            if let Some(fallback) = profile.sched_fallback_policy {
+                if let Err(why) = set_policy(process, fallback, profile.sched_priority) {
+                    tracing::debug!(
+                        pid = process,
+                        policy = ?fallback,
+                        source = %profile.name,
+                        error = %why,
+                        "failed to set fallback scheduler policy"
+                    );
+                }
+            }
+        }
+
+        count_syscall();
 
         #[allow(clippy::cast_possible_wrap)]
         let _res = ioprio::set_priority(
             Target::Process(Pid::from_raw(process as i32)),
-            ioprio::Priority::new(profile.io),
+            ioprio::Priority::new(profile.io_class()),
         );
     }
+
+    if ManageMode::All == mode {
+        if let Some(target) = profile.io_latency_target_us {
+            set_io_latency(buffer, process, target);
+        }
+
+        if profile.sched_policy.is_realtime() {
+            if let Some(limit) = profile.rt_time_limit_us {
+                set_rt_time_limit(process, limit);
+            }
+        }
+
+        if let Some(node) = profile.numa_node {
+            set_numa_node(buffer, process, node);
+        }
+    }
+}
+
+/// Logs the priorities that a profile is about to apply to a process, as structured fields.
+fn log_change(buffer: &mut Buffer, process: u32, profile: &Profile, mode: ManageMode) {
+    let name = crate::process::cmdline(buffer, process).unwrap_or_default();
+    let name = crate::process::name(&name);
+    let nice = profile.nice.map_or(0, |nice| nice.get());
+
+    if ManageMode::CpuOnly == mode {
+        tracing::debug!(
+            pid = process,
+            name,
+            nice,
+            source = %profile.name,
+            "applying priority"
+        );
+
+        return;
+    }
+
+    let (io_class, io_level) = match profile.io_class() {
+        ioprio::Class::Realtime(level) => ("realtime", level.level()),
+        ioprio::Class::BestEffort(level) => ("best-effort", level.level()),
+        ioprio::Class::Idle => ("idle", 0),
+    };
+
+    let rt_priority = if profile.sched_policy.is_realtime() {
+        profile.sched_priority.get()
+    } else {
+        0
+    };
+
+    tracing::debug!(
+        pid = process,
+        name,
+        nice,
+        io_class,
+        io_level,
+        policy = ?profile.sched_policy,
+        rt_priority,
+        source = %profile.name,
+        "applying priority"
+    );
+}
+
+/// Restricts the tasks of a process to the CPUs of a NUMA node, if the node exists.
+fn set_numa_node(buffer: &mut Buffer, process: u32, node: u16) {
+    let path = format!("/sys/devices/system/node/node{node}/cpulist");
+
+    if !Path::new(&path).exists() {
+        tracing::debug!(pid = process, node, "NUMA node does not exist");
+        return;
+    }
+
+    let cpus = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|cpus| system76_scheduler_config::game_mode::parse_cpu_list(cpus.trim()));
+
+    // An empty set would allow all CPUs, so a node without CPUs is skipped as well.
+    match cpus {
+        Some(cpus) if !cpus.is_empty() => set_affinity(buffer, process, &cpus),
+        _ => tracing::debug!(pid = process, node, "NUMA node has no CPUs to restrict to"),
+    }
 }
 
-pub fn set_policy(pid: u32, policy: SchedPolicy, sched_priority: SchedPriority) {
+/// Sets the niceness of every task of a process, and returns the niceness each task had before.
+///
+/// Tasks whose niceness could not be read are left as they are, so that every
+/// task which was changed can be restored by `restore_nice`.
+pub fn set_nice_saved(buffer: &mut Buffer, process: u32, nice: i32) -> Vec<(u32, i32)> {
+    buffer.path.clear();
+    let tasks = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(process) "/task");
+
+    let Ok(tasks) = std::fs::read_dir(tasks) else {
+        return Vec::new();
+    };
+
+    let mut saved = Vec::new();
+
+    for task in tasks.filter_map(Result::ok) {
+        let Some(task) = atoi::atoi::<u32>(task.file_name().as_bytes()) else {
+            continue;
+        };
+
+        // A niceness of -1 is also the error value, so errors are told apart by errno.
+        let previous = unsafe {
+            *libc::__errno_location() = 0;
+            libc::getpriority(libc::PRIO_PROCESS, task)
+        };
+
+        if previous == -1 && io::Error::last_os_error().raw_os_error() != Some(0) {
+            continue;
+        }
+
+        count_syscall();
+
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, task, nice) } == 0 {
+            saved.push((task, previous));
+        }
+    }
+
+    saved
+}
+
+/// Restores the niceness of tasks which was saved by `set_nice_saved`.
+pub fn restore_nice(saved: &[(u32, i32)]) {
+    for &(task, nice) in saved {
+        count_syscall();
+
+        unsafe {
+            libc::setpriority(libc::PRIO_PROCESS, task, nice);
+        }
+    }
+}
+
+/// Limits the CPU time that a realtime process may consume without making a blocking syscall.
+///
+/// The kernel sends `SIGXCPU` once the limit is exceeded, which terminates the
+/// process unless it handles the signal, so that a buggy realtime process cannot
+/// monopolize a CPU. The hard limit of the process is kept, and the soft limit
+/// does not exceed it.
+fn set_rt_time_limit(process: u32, limit_us: u64) {
+    #[allow(clippy::cast_possible_wrap)]
+    let pid = process as libc::pid_t;
+
+    let mut current = libc::rlimit64 {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    count_syscall();
+
+    let mut result =
+        unsafe { libc::prlimit64(pid, libc::RLIMIT_RTTIME, std::ptr::null(), &mut current) };
+
+    if result == 0 {
+        let limit = libc::rlimit64 {
+            rlim_cur: limit_us.min(current.rlim_max),
+            rlim_max: current.rlim_max,
+        };
+
+        count_syscall();
+
+        result = unsafe { libc::prlimit64(pid, libc::RLIMIT_RTTIME, &limit, std::ptr::null_mut()) };
+    }
+
+    if result == -1 {
+        let why = io::Error::last_os_error();
+
+        if why.raw_os_error() != Some(libc::ESRCH) {
+            tracing::warn!(pid = process, error = %why, "failed to set realtime time limit");
+        }
+    }
+}
+
+/// Sets the `io.latency` target of the cgroup of a process, for every physical block device.
+///
+/// The target applies to every process in the cgroup. It is skipped if the io
+/// controller is not enabled for the cgroup, or if the cgroup already has the target.
+fn set_io_latency(buffer: &mut Buffer, process: u32, target_us: u32) {
+    let Some(cgroup) = crate::process::cgroup(buffer, process) else {
+        return;
+    };
+
+    let Ok(mut written) = IO_LATENCY.lock() else {
+        return;
+    };
+
+    if written.get(cgroup) == Some(&target_us) {
+        return;
+    }
+
+    let path = ["/sys/fs/cgroup", cgroup, "/io.latency"].concat();
+
+    if !Path::new(&path).exists() {
+        return;
+    }
+
+    let Ok(devices) = std::fs::read_dir("/sys/block") else {
+        return;
+    };
+
+    let mut complete = true;
+
+    for device in devices.filter_map(Result::ok) {
+        if !is_physical_block_device(&device) {
+            continue;
+        }
+
+        let Ok(numbers) = std::fs::read_to_string(device.path().join("dev")) else {
+            continue;
+        };
+
+        let target = format!("{} target={target_us}", numbers.trim());
+
+        if let Err(why) = std::fs::write(&path, target) {
+            tracing::debug!(pid = process, path = %path, error = %why, "failed to set io.latency");
+            complete = false;
+        }
+    }
+
+    // Only targets which were written to every device are skipped from now on.
+    if !complete {
+        return;
+    }
+
+    // Cgroups of exited processes are forgotten once too many have been recorded.
+    if written.len() >= 1024 {
+        written.clear();
+    }
+
+    written.insert(Box::from(cgroup), target_us);
+}
+
+/// Whether an entry of `/sys/block` is backed by hardware, rather than being a
+/// virtual device such as a loop device, ramdisk, or zram device.
+fn is_physical_block_device(device: &std::fs::DirEntry) -> bool {
+    let name = device.file_name();
+    let name = name.as_bytes();
+
+    if name.starts_with(b"loop") || name.starts_with(b"ram") || name.starts_with(b"zram") {
+        return false;
+    }
+
+    std::fs::read_link(device.path()).map_or(false, |target| {
+        memchr::memmem::find(target.as_os_str().as_bytes(), b"/virtual/").is_none()
+    })
+}
+
+/// Restricts the tasks of a process to a set of CPUs, where an empty set allows all CPUs.
+pub fn set_affinity(buffer: &mut Buffer, process: u32, cpus: &[usize]) {
+    buffer.path.clear();
+    let tasks = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(process) "/task");
+
+    let Ok(tasks) = std::fs::read_dir(tasks) else {
+        return;
+    };
+
+    let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+
+    #[allow(clippy::cast_sign_loss)]
+    let set_size = libc::CPU_SETSIZE as usize;
+
+    unsafe {
+        if cpus.is_empty() {
+            (0..set_size).for_each(|cpu| libc::CPU_SET(cpu, &mut set));
+        } else {
+            for &cpu in cpus.iter().filter(|&&cpu| cpu < set_size) {
+                libc::CPU_SET(cpu, &mut set);
+            }
+        }
+    }
+
+    for task in tasks.filter_map(Result::ok) {
+        let Some(task) = atoi::atoi::<libc::pid_t>(task.file_name().as_bytes()) else {
+            continue;
+        };
+
+        count_syscall();
+
+        let result =
+            unsafe { libc::sched_setaffinity(task, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+
+        if result == -1 {
+            let why = io::Error::last_os_error();
+
+            if why.raw_os_error() != Some(libc::ESRCH) {
+                tracing::warn!(pid = task, error = %why, "failed to set CPU affinity");
+            }
+        }
+    }
+}
+
+/// Reverts the tasks of a process to `SCHED_OTHER`.
+pub fn revert_realtime(buffer: &mut Buffer, process: u32) {
+    buffer.path.clear();
+    let tasks = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(process) "/task");
+
+    let Ok(tasks) = std::fs::read_dir(tasks) else {
+        return;
+    };
+
+    for task in tasks.filter_map(Result::ok) {
+        let Some(task) = atoi::atoi::<u32>(task.file_name().as_bytes()) else {
+            continue;
+        };
+
+        if let Err(why) = set_policy(task, SchedPolicy::Other, SchedPriority::default()) {
+            if why.raw_os_error() != Some(libc::ESRCH) {
+                tracing::warn!(pid = task, error = %why, "failed to revert scheduler policy");
+            }
+        }
+    }
+}
+
+/// Whether the daemon is permitted to assign a niceness, I/O class, and scheduler policy.
+///
+/// Nothing is assigned. The capabilities and resource limits of the daemon are
+/// checked, as well as whether the realtime budget of the kernel allows any
+/// realtime tasks at all.
+pub fn can_apply(nice: i8, io: IoClass, policy: SchedPolicy) -> bool {
+    let sys_nice = has_capability(CAP_SYS_NICE);
+
+    // Raising the priority is limited by `RLIMIT_NICE` to a niceness of `20 - rlim_cur`.
+    if nice < 0 && !sys_nice {
+        let required = libc::rlim_t::from(20 + nice.unsigned_abs());
+        if rlimit(libc::RLIMIT_NICE) < required {
+            return false;
+        }
+    }
+
+    if let IoClass::Realtime = io {
+        if !(sys_nice || has_capability(CAP_SYS_ADMIN)) {
+            return false;
+        }
+    }
+
+    if policy.is_realtime() {
+        if !sys_nice && rlimit(libc::RLIMIT_RTPRIO) == 0 {
+            return false;
+        }
+
+        // A runtime of 0 leaves no time for realtime tasks to run in.
+        let runtime = std::fs::read_to_string("/proc/sys/kernel/sched_rt_runtime_us");
+        if runtime.map_or(false, |runtime| runtime.trim() == "0") {
+            return false;
+        }
+    }
+
+    true
+}
+
+const CAP_SYS_NICE: u32 = 23;
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// Whether a capability is in the effective set of the daemon.
+fn has_capability(capability: u32) -> bool {
+    let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+        return false;
+    };
+
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .map_or(false, |caps| caps & (1 << capability) != 0)
+}
+
+/// The soft limit of a resource, where an unlimited resource has the highest value.
+fn rlimit(resource: libc::__rlimit_resource_t) -> libc::rlim_t {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(resource, &mut limit) } == -1 {
+        return 0;
+    }
+
+    limit.rlim_cur
+}
+
+pub fn set_policy(pid: u32, policy: SchedPolicy, sched_priority: SchedPriority) -> io::Result<()> {
+    count_syscall();
+
     let param = libc::sched_param {
         sched_priority: libc::c_int::from({
             if policy.is_realtime() {
@@ -54,8 +511,13 @@ pub fn set_policy(pid: u32, policy: SchedPolicy, sched_priority: SchedPriority)
         }),
     };
 
-    unsafe {
-        #[allow(clippy::cast_possible_wrap)]
-        libc::sched_setscheduler(pid as libc::c_int, policy as libc::c_int, &param);
+    #[allow(clippy::cast_possible_wrap)]
+    let result =
+        unsafe { libc::sched_setscheduler(pid as libc::c_int, policy as libc::c_int, &param) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
     }
+
+    Ok(())
 }
@@ -1,4 +1,8 @@
-use crate::{service::OwnedPriority, utils::Buffer};
+use crate::{
+    config::scheduler::{NameMatch, Profile},
+    service::OwnedPriority,
+    utils::Buffer,
+};
 use bstr::ByteSlice;
 use concat_in_place::strcat;
 use qcell::{LCell, LCellOwner};
@@ -17,6 +21,8 @@ pub struct Process<'owner> {
     pub id: u32,
     pub parent_id: u32,
     pub name: String,
+    /// Name of the process as set by itself, which is only read if name rules match it.
+    pub comm: String,
     pub cgroup: String,
     pub cmdline: String,
     pub forked_cmdline: String,
@@ -24,6 +30,14 @@ pub struct Process<'owner> {
     pub parent: Option<Weak<LCell<'owner, Process<'owner>>>>,
     pub assigned_priority: OwnedPriority,
     pub pipewire_ancestor: Option<u32>,
+    /// CPU time of the process at the last refresh, in clock ticks.
+    pub cpu_time: u64,
+    /// The process did not use the CPU since the previous refresh.
+    pub idle: bool,
+    /// The assignment depended on the age of the process, so it is reassigned on each refresh.
+    pub age_dependent: bool,
+    /// The outermost group launcher that the process belongs to, with the profile of its group.
+    pub launcher_group: Option<(u32, Profile)>,
 }
 
 impl<'owner> Hash for Process<'owner> {
@@ -55,6 +69,23 @@ impl<'owner> Process<'owner> {
         hasher.finish()
     }
 
+    /// The name that name rules are matched against.
+    ///
+    /// In the `both` mode, this is `None` if the `comm` of the process disagrees
+    /// with the basename of its executable.
+    pub fn matched_name(&self, mode: NameMatch) -> Option<&str> {
+        match mode {
+            NameMatch::Comm => Some(&self.comm),
+            NameMatch::Exe => Some(&self.name),
+            NameMatch::Both => {
+                // The kernel truncates `comm` to 15 bytes.
+                let len = self.name.len().min(15);
+                let agrees = self.comm.as_bytes() == &self.name.as_bytes()[..len];
+                agrees.then_some(self.name.as_str())
+            }
+        }
+    }
+
     pub fn parent(&self) -> Option<Arc<LCell<'owner, Process<'owner>>>> {
         self.parent.as_ref().and_then(Weak::upgrade)
     }
@@ -104,6 +135,18 @@ impl<'owner> Map<'owner> {
                     entry.cgroup = process.cgroup;
                     entry.parent = process.parent;
 
+                    // Conditions on the age of a process may stop matching as it ages.
+                    if entry.age_dependent {
+                        entry.assigned_priority = OwnedPriority::NotAssignable;
+                    }
+
+                    // A process may rename itself, which can change its assignment.
+                    // Processes which are inserted without a comm keep the stored comm.
+                    if !process.comm.is_empty() && entry.comm != process.comm {
+                        entry.comm = process.comm;
+                        entry.assigned_priority = OwnedPriority::NotAssignable;
+                    }
+
                     if entry.name != process.name {
                         std::mem::swap(&mut entry.forked_cmdline, &mut entry.cmdline);
                         std::mem::swap(&mut entry.forked_name, &mut entry.name);
@@ -149,6 +192,69 @@ pub fn cgroup(buffer: &mut Buffer, pid: u32) -> Option<&str> {
     memchr::memchr(b':', buffer.as_bytes()).map(|pos| &buffer[pos + 2..buffer.len() - 1])
 }
 
+/// The login session scope that a cgroup path belongs to, such as `session-2.scope`.
+///
+/// logind places the processes of a login session in the scope of that session.
+/// Processes started by the user's own service manager have no session scope.
+pub fn session(cgroup: &str) -> Option<&str> {
+    cgroup
+        .split('/')
+        .find(|component| component.starts_with("session-") && component.ends_with(".scope"))
+}
+
+/// The user slice that a cgroup path belongs to, such as `user-1000.slice`.
+///
+/// systemd places every process of a logged-in user beneath the slice of that
+/// user, whether it was started in a login session scope or by the user's own
+/// service manager. Processes of system services have no user slice.
+pub fn user_slice(cgroup: &str) -> Option<&str> {
+    cgroup
+        .split('/')
+        .find(|component| component.starts_with("user-") && component.ends_with(".slice"))
+}
+
+/// Name of the distrobox or toolbox container that a process is running in.
+///
+/// Containers share the host's kernel, so their processes are visible in the
+/// host's `/proc`. Distrobox defines the container name in the environment,
+/// whereas toolbox containers are identified by podman's `container` variable
+/// and are named in the container's `/run/.containerenv`.
+pub fn container(buffer: &mut Buffer, pid: u32) -> Option<String> {
+    buffer.path.clear();
+
+    let path = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/environ");
+
+    let environ = crate::utils::read_into_vec(&mut buffer.file_raw, path).ok()?;
+
+    let mut is_container = false;
+
+    for variable in environ.split(|&byte| byte == 0) {
+        if let Some(name) = variable.strip_prefix(b"CONTAINER_ID=") {
+            return Some(String::from_utf8_lossy(name).into_owned());
+        }
+
+        if variable.starts_with(b"container=") {
+            is_container = true;
+        }
+    }
+
+    if !is_container {
+        return None;
+    }
+
+    buffer.path.clear();
+
+    let path =
+        strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/root/run/.containerenv");
+
+    let containerenv = crate::utils::read_into_string(&mut buffer.file, path).ok()?;
+
+    containerenv
+        .lines()
+        .find_map(|line| line.strip_prefix("name="))
+        .map(|name| name.trim_matches('"').to_owned())
+}
+
 pub fn cmdline(buffer: &mut Buffer, pid: u32) -> Option<String> {
     buffer.path.clear();
 
@@ -158,23 +264,144 @@ pub fn cmdline(buffer: &mut Buffer, pid: u32) -> Option<String> {
         return None;
     };
 
-    Some(
-        exe.as_os_str()
-            .to_string_lossy()
-            .split_whitespace()
-            .next()
-            .map(String::from)
-            .unwrap_or_default(),
-    )
+    Some(strip_deleted(&exe.as_os_str().to_string_lossy()).to_owned())
+}
+
+/// Removes the suffix that the kernel appends to the exe link of a process whose
+/// binary was deleted, such as after its package was updated while it was running.
+fn strip_deleted(exe: &str) -> &str {
+    exe.strip_suffix(" (deleted)").unwrap_or(exe)
+}
+
+/// Time that a process has spent in user and kernel mode, in clock ticks.
+pub fn cpu_time(buffer: &mut Buffer, pid: u32) -> Option<u64> {
+    buffer.path.clear();
+
+    let path = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/stat");
+
+    let stat = crate::utils::read_into_vec(&mut buffer.file_raw, path).ok()?;
+
+    // The process name may contain spaces and parentheses, so fields are read after its end.
+    let name_end = memchr::memrchr(b')', stat)?;
+
+    // Fields after the name begin with the 3rd, and utime and stime are the 14th and 15th.
+    let mut fields = stat[name_end + 1..].fields();
+    let utime = fields.nth(11).and_then(atoi::atoi::<u64>)?;
+    let stime = fields.next().and_then(atoi::atoi::<u64>)?;
+
+    Some(utime + stime)
+}
+
+/// Time that a process started at, in clock ticks since boot.
+pub fn start_time(buffer: &mut Buffer, pid: u32) -> Option<u64> {
+    buffer.path.clear();
+
+    let path = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/stat");
+
+    let stat = crate::utils::read_into_vec(&mut buffer.file_raw, path).ok()?;
+
+    let name_end = memchr::memrchr(b')', stat)?;
+
+    // Fields after the name begin with the 3rd, and the start time is the 22nd.
+    stat[name_end + 1..]
+        .fields()
+        .nth(19)
+        .and_then(atoi::atoi::<u64>)
+}
+
+/// Seconds since a process started, given the boot time of the system.
+pub fn age(buffer: &mut Buffer, pid: u32, boot_time: u64) -> Option<u64> {
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let ticks = u64::try_from(ticks).ok().filter(|&ticks| ticks != 0)?;
+
+    let started = boot_time + start_time(buffer, pid)? / ticks;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+
+    Some(now.saturating_sub(started))
+}
+
+/// Time that the system booted at, in seconds since the Unix epoch.
+pub fn boot_time() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+
+    stat.lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|btime| btime.trim().parse::<u64>().ok())
+}
+
+/// ID of the session that a process belongs to, which is the pid of the session leader.
+pub fn session_id(buffer: &mut Buffer, pid: u32) -> Option<u32> {
+    buffer.path.clear();
+
+    let path = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/stat");
+
+    let stat = crate::utils::read_into_vec(&mut buffer.file_raw, path).ok()?;
+
+    let name_end = memchr::memrchr(b')', stat)?;
+
+    // Fields after the name begin with the 3rd, and the session is the 6th.
+    stat[name_end + 1..]
+        .fields()
+        .nth(3)
+        .and_then(atoi::atoi::<u32>)
+}
+
+/// Name of the process, which processes may set for themselves with `PR_SET_NAME`.
+pub fn comm(buffer: &mut Buffer, pid: u32) -> Option<String> {
+    buffer.path.clear();
+
+    let path = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/comm");
+
+    let comm = crate::utils::read_into_string(&mut buffer.file, path).ok()?;
+
+    Some(comm.trim_end_matches('\n').to_owned())
 }
 
-#[allow(dead_code)]
 pub fn exists(buffer: &mut Buffer, pid: u32) -> bool {
     buffer.path.clear();
     Path::new(strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/status")).exists()
 }
 
+/// The name that a process is matched by, which is its script if it is an interpreter.
+pub fn effective_name(
+    buffer: &mut Buffer,
+    pid: u32,
+    name: String,
+    interpreters: &[Box<str>],
+) -> String {
+    if !interpreters
+        .iter()
+        .any(|interpreter| **interpreter == *name)
+    {
+        return name;
+    }
+
+    script_name(buffer, pid).unwrap_or(name)
+}
+
+/// Basename of the first argument of an interpreter which is not an option.
+fn script_name(buffer: &mut Buffer, pid: u32) -> Option<String> {
+    buffer.path.clear();
+
+    let path = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/cmdline");
+
+    let cmdline = crate::utils::read_into_vec(&mut buffer.file_raw, path).ok()?;
+
+    let script = cmdline
+        .split(|&byte| byte == 0)
+        .skip(1)
+        .find(|arg| !arg.is_empty() && arg[0] != b'-')?;
+
+    let script = String::from_utf8_lossy(script);
+
+    Some(name(&script).to_owned())
+}
+
 pub fn name(cmdline: &str) -> &str {
+    let cmdline = strip_deleted(cmdline);
     cmdline.rsplit('/').next().unwrap_or(cmdline)
 }
 
@@ -190,6 +417,14 @@ pub fn parent_id(buffer: &mut Buffer, pid: u32) -> Option<u32> {
     None
 }
 
+pub fn thread_count(buffer: &mut Buffer, pid: u32) -> Option<u32> {
+    buffer.path.clear();
+
+    let path = strcat!(&mut buffer.path, "/proc/" buffer.itoa.format(pid) "/status");
+
+    crate::utils::file_key(&mut buffer.file_raw, path, "Threads:").and_then(atoi::atoi::<u32>)
+}
+
 pub fn children(buffer: &'_ mut Buffer, pid: u32) -> impl Iterator<Item = u32> + '_ {
     buffer.path.clear();
     buffer.file_raw.clear();
@@ -206,3 +441,16 @@ pub fn children(buffer: &'_ mut Buffer, pid: u32) -> impl Iterator<Item = u32> +
                 .filter_map(atoi::atoi::<u32>)
         })
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn deleted_exe() {
+        assert_eq!(
+            "/usr/bin/foo",
+            super::strip_deleted("/usr/bin/foo (deleted)")
+        );
+        assert_eq!("/usr/bin/foo", super::strip_deleted("/usr/bin/foo"));
+        assert_eq!("foo", super::name("/usr/bin/foo (deleted)"));
+    }
+}
@@ -38,6 +38,16 @@ pub async fn main() -> anyhow::Result<()> {
     result
 }
 
+/// Niceness bias for the pipewire profile, derived from the priority of a process's node.
+///
+/// The driver of the graph has a higher node priority than the nodes that follow it, so
+/// the niceness of the pipewire profile is lowered by 1 for every 500 of node priority,
+/// up to a maximum of 5. Nodes without a priority receive no bias.
+#[allow(clippy::cast_possible_truncation)]
+pub fn nice_bias(priority: i32) -> i8 {
+    (priority / 500).clamp(0, 5) as i8
+}
+
 /// Monitor pipewire sockets and the process IDs connected to them.
 async fn pipewire_service(tx: Sender<ProcessEvent>) {
     // TODO: Support stopping and restarting this on config changes.
@@ -115,7 +125,7 @@ pub(crate) async fn monitor(tx: Sender<Event>) {
 
         tracing::debug!("connected to pipewire");
 
-        let result = std::process::Command::new(exe)
+        let result = tokio::process::Command::new(exe)
             .arg("pipewire")
             .stdin(std::process::Stdio::null())
             .stderr(std::process::Stdio::null())
@@ -132,11 +142,6 @@ pub(crate) async fn monitor(tx: Sender<Event>) {
             break;
         };
 
-        let Ok(stdout) = tokio::process::ChildStdout::from_std(stdout) else {
-            tracing::error!("failed to create tokio stdout from pipewire process");
-            break;
-        };
-
         let mut stdout = tokio::io::BufReader::new(stdout);
         let mut line = Vec::new();
 
@@ -144,17 +149,22 @@ pub(crate) async fn monitor(tx: Sender<Event>) {
             line.clear();
 
             match stdout.read_until(b'\n', &mut line).await {
-                Ok(0) | Err(_) => break,
+                Ok(0) => break,
                 Ok(_) => (),
+                Err(why) => {
+                    tracing::error!("failed to read from pipewire watcher: {why}");
+                    let _res = child.start_kill();
+                    break;
+                }
             }
 
             if let Some(event) = ProcessEvent::from_bytes(&line) {
                 match event {
-                    ProcessEvent::Add(pid) => {
-                        if !managed.insert(pid) {
+                    ProcessEvent::Add(process) => {
+                        if !managed.insert(process.id) {
                             continue;
                         }
-                        tracing::debug!("{pid} started using pipewire");
+                        tracing::debug!("{} started using pipewire", process.id);
                     }
                     ProcessEvent::Remove(pid) => {
                         if !managed.remove(&pid) {
@@ -167,6 +177,13 @@ pub(crate) async fn monitor(tx: Sender<Event>) {
                 let _res = tx.send(Event::Pipewire(event)).await;
             }
         }
+
+        // Reap the watcher before it is respawned, so that restarts do not leave zombies behind.
+        match child.wait().await {
+            Ok(status) if status.success() => tracing::debug!("pipewire watcher exited"),
+            Ok(status) => tracing::warn!("pipewire watcher exited with {status}"),
+            Err(why) => tracing::error!("failed to reap pipewire watcher: {why}"),
+        }
     }
 
     tracing::info!("stopped listening to pipewire");
@@ -2,26 +2,65 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use crate::cfs::paths::SchedPaths;
-use crate::config::scheduler::Profile;
+use crate::config::scheduler::{
+    ForegroundAssignments, ManageMode, NameMatch, Niceness, Profile, SchedPolicy,
+};
+use crate::dbus::{CpuMode, ForegroundSource};
 use crate::process::{self, Process};
+use crate::scheduler_pipewire;
 use crate::utils::Buffer;
 use qcell::{LCell, LCellOwner};
-use std::collections::BTreeMap;
-use std::{os::unix::prelude::OsStrExt, sync::Arc};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::time::{Duration, Instant};
+use std::{borrow::Cow, os::unix::prelude::OsStrExt, sync::Arc};
 use system76_scheduler_config::scheduler::Condition;
 
+/// Critical processes which are excepted outside of user slices, unless they are explicitly assigned.
+const PROTECTED_PROCESSES: &[&str] = &[
+    "systemd",
+    "init",
+    "dbus-daemon",
+    "dbus-broker",
+    "dbus-broker-launch",
+    "systemd-logind",
+    "gnome-session-binary",
+    "cosmic-session",
+    "ksmserver",
+    "plasma_session",
+    "xfce4-session",
+    "lxqt-session",
+];
+
+/// Maximum number of unmatched processes recorded by the learning mode.
+const LEARNED_LIMIT: usize = 4096;
+
 pub struct Service<'owner> {
     pub config: crate::config::Config,
     assign_scan: Vec<u32>,
     assign_scanned: Vec<u32>,
     assign_tasks: Vec<u32>,
+    baseline_background: bool,
+    boot_time: Option<u64>,
     cfs_paths: Option<SchedPaths>,
+    cfs_revert: Option<(u64, CpuMode, String)>,
+    cfs_revert_generation: u64,
     foreground_processes: Vec<u32>,
     foreground: Option<u32>,
+    foreground_fullscreen: bool,
+    foreground_session: Option<String>,
+    foreground_user: Option<String>,
+    foreground_sources: BTreeMap<ForegroundSource, (u32, bool)>,
+    game_mode: Option<(CpuMode, String)>,
+    game_pinned: Vec<u32>,
     gc_counter: usize,
+    learned: HashSet<Box<str>>,
+    learned_changed: bool,
     owner: LCellOwner<'owner>,
-    pipewire_processes: Vec<u32>,
+    pipewire_processes: BTreeMap<u32, i32>,
     process_map: process::Map<'owner>,
+    provisional: BTreeMap<u32, Vec<(u32, i32)>>,
+    realtime_reverted: BTreeSet<u32>,
+    realtime_watched: BTreeMap<u32, RealtimeWatch>,
 }
 
 impl<'owner> Service<'owner> {
@@ -30,22 +69,43 @@ impl<'owner> Service<'owner> {
             assign_scan: Vec::with_capacity(16),
             assign_scanned: Vec::with_capacity(16),
             assign_tasks: Vec::with_capacity(16),
+            baseline_background: false,
+            boot_time: process::boot_time(),
             cfs_paths: SchedPaths::new().ok(),
+            cfs_revert: None,
+            cfs_revert_generation: 0,
             config: crate::config::Config::default(),
             foreground_processes: Vec::with_capacity(256),
             foreground: None,
+            foreground_fullscreen: false,
+            foreground_session: None,
+            foreground_user: None,
+            foreground_sources: BTreeMap::new(),
+            game_mode: None,
+            game_pinned: Vec::new(),
             gc_counter: 0,
+            learned: HashSet::new(),
+            learned_changed: false,
             owner,
-            pipewire_processes: Vec::with_capacity(4),
+            pipewire_processes: BTreeMap::new(),
             process_map: process::Map::default(),
+            provisional: BTreeMap::new(),
+            realtime_reverted: BTreeSet::new(),
+            realtime_watched: BTreeMap::new(),
         }
     }
 
-    pub fn assign_process_priority(&mut self, process: &LCell<'owner, Process<'owner>>) {
+    pub fn assign_process_priority(
+        &mut self,
+        buffer: &mut Buffer,
+        process: &LCell<'owner, Process<'owner>>,
+    ) {
         if OwnedPriority::NotAssignable != process.ro(&self.owner).assigned_priority {
             return;
         }
 
+        let age_checked = std::cell::Cell::new(false);
+
         let priority = (|| {
             let process = process.ro(&self.owner);
 
@@ -62,11 +122,10 @@ impl<'owner> Service<'owner> {
                 return OwnedPriority::Config(profile.clone());
             }
 
-            if let Some(profile) = self
-                .config
-                .process_scheduler
-                .assignments
-                .get_by_name(&process.name)
+            let matched_name = process.matched_name(self.config.process_scheduler.name_match);
+
+            if let Some(profile) = matched_name
+                .and_then(|name| self.config.process_scheduler.assignments.get_by_name(name))
             {
                 return OwnedPriority::Config(profile.clone());
             }
@@ -76,7 +135,19 @@ impl<'owner> Service<'owner> {
             }
 
             // True when all conditions for a profile are met by a process.
-            let condition_met = |condition: &Condition| {
+            let mut condition_met = |condition: &Condition| {
+                if let Some(threshold) = condition.age {
+                    age_checked.set(true);
+
+                    let age = self
+                        .boot_time
+                        .and_then(|boot_time| process::age(buffer, process.id, boot_time));
+
+                    if !age.map_or(false, |age| threshold.matches(age)) {
+                        return false;
+                    }
+                }
+
                 if let Some(ref cgroup) = condition.cgroup {
                     if !cgroup.matches(&process.cgroup) {
                         return false;
@@ -84,7 +155,17 @@ impl<'owner> Service<'owner> {
                 }
 
                 if let Some(ref name) = condition.name {
-                    if !name.matches(&process.name) {
+                    if !matched_name.map_or(false, |matched| name.matches(matched)) {
+                        return false;
+                    }
+                }
+
+                if let Some(ref container) = condition.container {
+                    let Some(name) = process::container(buffer, process.id) else {
+                        return false;
+                    };
+
+                    if !container.matches(&name) {
                         return false;
                     }
                 }
@@ -116,6 +197,42 @@ impl<'owner> Service<'owner> {
                     }
                 }
 
+                if let Some(ref snap) = condition.snap {
+                    let Some(name) = crate::config::scheduler::snap_name(&process.cgroup) else {
+                        return false;
+                    };
+
+                    if !snap.matches(name) {
+                        return false;
+                    }
+                }
+
+                if let Some(parent_pid) = condition.parent_pid {
+                    if process.parent_id != parent_pid {
+                        return false;
+                    }
+                }
+
+                if let Some(expected) = condition.parent_is_session_leader {
+                    let parent = process.parent_id;
+                    let is_leader = process::session_id(buffer, parent) == Some(parent);
+
+                    if is_leader != expected {
+                        return false;
+                    }
+                }
+
+                if let Some(threshold) = condition.threads {
+                    // A process whose thread count cannot be read does not match.
+                    let Some(threads) = process::thread_count(buffer, process.id) else {
+                        return false;
+                    };
+
+                    if !threshold.matches(u64::from(threads)) {
+                        return false;
+                    }
+                }
+
                 true
             };
 
@@ -146,9 +263,62 @@ impl<'owner> Service<'owner> {
             OwnedPriority::Assignable
         })();
 
+        let launcher_group = self
+            .launcher_group_of(process.ro(&self.owner))
+            .map(|(pid, profile)| (pid, profile.clone()));
+
+        let process_mut = process.rw(&mut self.owner);
+        process_mut.age_dependent = age_checked.get();
+        process_mut.launcher_group = launcher_group;
+
+        if OwnedPriority::Assignable == priority && self.config.process_scheduler.learning.enable {
+            let process = process.ro(&self.owner);
+
+            let identifier = if process.cmdline.is_empty() {
+                process.name.as_str()
+            } else {
+                process.cmdline.as_str()
+            };
+
+            if !identifier.is_empty()
+                && self.learned.len() < LEARNED_LIMIT
+                && !self.learned.contains(identifier)
+            {
+                if self.config.process_scheduler.learning.path.is_none() {
+                    tracing::info!("no assignment for {identifier}");
+                }
+
+                self.learned.insert(Box::from(identifier));
+                self.learned_changed = true;
+            }
+        }
+
         process.rw(&mut self.owner).assigned_priority = priority;
     }
 
+    /// The outermost launcher of a group that a process belongs to, which may be itself.
+    fn launcher_group_of(&self, process: &Process<'owner>) -> Option<(u32, &Profile)> {
+        let assignments = &self.config.process_scheduler.assignments;
+
+        if !assignments.has_launchers() {
+            return None;
+        }
+
+        let mut group = assignments
+            .get_by_launcher(&process.name)
+            .map(|profile| (process.id, profile));
+
+        for ancestor in process.ancestors(&self.owner) {
+            let ancestor = ancestor.ro(&self.owner);
+
+            if let Some(profile) = assignments.get_by_launcher(&ancestor.name) {
+                group = Some((ancestor.id, profile));
+            }
+        }
+
+        group
+    }
+
     /// Assigns children of a process in case they've not been assigned.
     pub fn assign_children(&mut self, buffer: &mut Buffer, pid: u32) {
         let mut tasks = Vec::new();
@@ -195,7 +365,95 @@ impl<'owner> Service<'owner> {
         std::mem::swap(&mut scanned, &mut self.assign_scanned);
     }
 
+    /// Applies the niceness of a cmdline or name rule to a process as soon as it is executed.
+    ///
+    /// The full assignment, which may depend on the cgroup and ancestry of the
+    /// process, is applied later by `assign_new_process`. Until then, the
+    /// previous niceness of each task is kept so that it may be restored before
+    /// the full assignment is applied.
+    pub fn assign_provisional(
+        &mut self,
+        buffer: &mut Buffer,
+        pid: u32,
+        parent_pid: u32,
+        name: String,
+        cmdline: String,
+    ) {
+        if self.process_map.get_pid(pid).is_some() {
+            return;
+        }
+
+        let name_match = self.config.process_scheduler.name_match;
+        let name = process::effective_name(
+            buffer,
+            pid,
+            name,
+            &self.config.process_scheduler.interpreters,
+        );
+
+        let process = Process {
+            id: pid,
+            comm: if name_match == NameMatch::Exe {
+                String::new()
+            } else {
+                process::comm(buffer, pid).unwrap_or_default()
+            },
+            name,
+            cmdline,
+            ..Process::default()
+        };
+
+        let assignments = &self.config.process_scheduler.assignments;
+
+        if assignments.is_exception_by_cmdline(&process.cmdline)
+            || assignments.is_exception_by_name(&process.name)
+        {
+            return;
+        }
+
+        let profile = assignments.get_by_cmdline(&process.cmdline).or_else(|| {
+            process
+                .matched_name(name_match)
+                .and_then(|name| assignments.get_by_name(name))
+        });
+
+        if let Some(profile) = profile {
+            if let Some(nice) = profile.nice.filter(|_| !profile.observe_only) {
+                let saved =
+                    crate::priority::set_nice_saved(buffer, pid, libc::c_int::from(nice.get()));
+                self.provisional.insert(pid, saved);
+            }
+
+            return;
+        }
+
+        // Audio workers of pipewire processes need the whole profile, such as a realtime policy.
+        let pipewire = self
+            .pipewire_ancestor_of(parent_pid)
+            .and_then(|ancestor| self.pipewire_profile(ancestor));
+
+        if let Some(profile) = pipewire {
+            // Like any other provisional assignment, the previous niceness is kept
+            // for `assign_new_process`, and reverted realtime processes stay reverted.
+            let profile = self.realtime_guard(pid, &profile);
+
+            if let Some(nice) = profile.nice {
+                let saved =
+                    crate::priority::set_nice_saved(buffer, pid, libc::c_int::from(nice.get()));
+                self.provisional.insert(pid, saved);
+            }
+
+            crate::priority::set(buffer, pid, &profile, self.config.process_scheduler.manage);
+
+            if profile.sched_policy.is_realtime() {
+                self.realtime_watch(pid);
+            }
+        }
+    }
+
     /// Assign a priority to a newly-created process, and record that process in the map.
+    ///
+    /// Returns `false` without recording anything if the process has already exited.
     pub fn assign_new_process(
         &mut self,
         buffer: &mut Buffer,
@@ -203,21 +461,37 @@ impl<'owner> Service<'owner> {
         parent_pid: u32,
         name: String,
         mut cmdline: String,
-    ) {
-        let parent = self.process_map.get_pid(parent_pid).cloned();
+    ) -> bool {
+        let provisional = self.provisional.remove(&pid);
 
-        let mut cgroup = String::new();
+        // Short-lived processes may exit before their exec event is handled.
+        if !process::exists(buffer, pid) {
+            return false;
+        }
 
-        if process::exists(buffer, pid) {
-            if cmdline.is_empty() {
-                cmdline = process::cmdline(buffer, pid).unwrap_or_default();
-            }
+        let name = process::effective_name(
+            buffer,
+            pid,
+            name,
+            &self.config.process_scheduler.interpreters,
+        );
+
+        let parent = self.process_map.get_pid(parent_pid).cloned();
 
-            cgroup = process::cgroup(buffer, pid)
-                .map(String::from)
-                .unwrap_or_default();
+        if cmdline.is_empty() {
+            cmdline = process::cmdline(buffer, pid).unwrap_or_default();
         }
 
+        let cgroup = process::cgroup(buffer, pid)
+            .map(String::from)
+            .unwrap_or_default();
+
+        let comm = if self.config.process_scheduler.name_match == NameMatch::Exe {
+            String::new()
+        } else {
+            process::comm(buffer, pid).unwrap_or_default()
+        };
+
         // Add the process to the map, if it does not already exist.
         let process = self.process_map.insert(
             &mut self.owner,
@@ -227,52 +501,87 @@ impl<'owner> Service<'owner> {
                 cgroup,
                 cmdline,
                 name,
+                comm,
                 parent: parent.as_ref().map(Arc::downgrade),
                 ..Process::default()
             },
         );
 
-        let mut pipewire_ancestor = None;
-
-        'outer: for process in process.ro(&self.owner).ancestors(&self.owner) {
-            let process = process.ro(&self.owner);
-            for &ancestor in &self.pipewire_processes {
-                if process.id == ancestor || process.parent_id == ancestor {
-                    pipewire_ancestor = Some(ancestor);
-                    break 'outer;
-                }
-            }
-        }
+        let pipewire_ancestor = self.pipewire_ancestor_of(parent_pid);
 
         if pipewire_ancestor.is_some() {
             process.rw(&mut self.owner).pipewire_ancestor = pipewire_ancestor;
         }
 
-        self.assign_process_priority(&process);
-        self.apply_process_priority(buffer, process.ro(&self.owner));
+        self.assign_process_priority(buffer, &process);
+
+        // Only the full assignment should take effect from here on.
+        if let Some(saved) = provisional {
+            crate::priority::restore_nice(&saved);
+        }
+
+        if self.apply_process_priority(buffer, process.ro(&self.owner)) {
+            self.realtime_watch(pid);
+        }
+
+        true
     }
 
-    pub fn apply_process_priority(&self, buffer: &mut Buffer, process: &Process<'owner>) {
+    /// Applies the assigned profile of a process.
+    ///
+    /// Returns `true` if the profile has a realtime policy.
+    pub fn apply_process_priority(&self, buffer: &mut Buffer, process: &Process<'owner>) -> bool {
+        // Idle processes keep the priority that was last applied to them.
+        if process.idle && self.config.process_scheduler.manage_active_only {
+            return false;
+        }
+
         let profile_default;
+        let profile_pipewire;
 
         let profile = match process.assigned_priority.as_ref() {
             Priority::Assignable => {
-                if let Some(ref profile) = self.config.process_scheduler.pipewire {
-                    if self.process_is_pipewire_assigned(process) {
-                        crate::priority::set(buffer, process.id, profile);
-                        return;
-                    }
+                let pipewire = if self.process_is_pipewire_assigned(process) {
+                    self.pipewire_profile(process.pipewire_ancestor.unwrap_or(process.id))
+                } else {
+                    None
+                };
+
+                // Processes of other sessions are left as they are.
+                if pipewire.is_none()
+                    && self.foreground.is_some()
+                    && self.process_in_other_session(process)
+                {
+                    return false;
                 }
 
-                if let (Some(assignments), Some(foreground)) =
-                    (&self.config.process_scheduler.foreground, &self.foreground)
+                // Members of a launcher group receive its profile instead of the background.
+                let group = process.launcher_group.as_ref().map(|(_, profile)| profile);
+
+                if let Some(profile) = pipewire {
+                    profile_pipewire = profile;
+                    &profile_pipewire
+                } else if let (Some(assignments), Some(foreground)) =
+                    (&self.config.process_scheduler.foreground, self.foreground)
                 {
-                    if process.id == *foreground || self.foreground_processes.contains(&process.id)
-                    {
-                        &assignments.foreground
+                    if process.id == foreground || self.foreground_processes.contains(&process.id) {
+                        self.foreground_profile(assignments)
+                    } else if let Some(profile) = group {
+                        profile
                     } else {
                         &assignments.background
                     }
+                } else if let Some(profile) = group {
+                    profile
+                } else if let Some(assignments) = self
+                    .config
+                    .process_scheduler
+                    .foreground
+                    .as_ref()
+                    .filter(|_| self.baseline_background && self.foreground.is_none())
+                {
+                    // Processes start from the background until a foreground process is known.
+                    &assignments.background
                 } else {
                     profile_default = Profile::new(Arc::from("default"));
                     &profile_default
@@ -281,30 +590,190 @@ impl<'owner> Service<'owner> {
 
             Priority::Config(profile) => profile,
 
-            _ => return,
+            _ => return false,
         };
 
-        crate::priority::set(buffer, process.id, profile);
+        // Observed processes are matched, but keep the priorities that they have.
+        if profile.observe_only {
+            return false;
+        }
+
+        let profile = self.realtime_guard(process.id, profile);
+
+        crate::priority::set(
+            buffer,
+            process.id,
+            &profile,
+            self.config.process_scheduler.manage,
+        );
+
+        if profile.verbose {
+            tracing::info!(
+                pid = process.id,
+                name = %process.name,
+                profile = %profile.name,
+                "applied profile"
+            );
+        }
+
+        profile.sched_policy.is_realtime()
     }
 
-    pub fn cfs_apply(&self, config: &crate::config::cfs::Profile) {
-        let Some(paths) = &self.cfs_paths else {
+    /// Replaces the realtime policy of a profile for processes that the watchdog reverted.
+    fn realtime_guard<'a>(&self, pid: u32, profile: &'a Profile) -> Cow<'a, Profile> {
+        if !profile.sched_policy.is_realtime() || !self.realtime_reverted.contains(&pid) {
+            return Cow::Borrowed(profile);
+        }
+
+        let mut profile = profile.clone();
+        profile.sched_policy = SchedPolicy::Other;
+        profile.sched_fallback_policy = None;
+        Cow::Owned(profile)
+    }
+
+    /// Watches a process that a realtime policy was applied to, if a cooldown is configured.
+    fn realtime_watch(&mut self, pid: u32) {
+        let cooldown = self.config.process_scheduler.realtime_cooldown;
+
+        if cooldown == 0
+            || ManageMode::CpuOnly == self.config.process_scheduler.manage
+            || self.realtime_reverted.contains(&pid)
+        {
             return;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(u64::from(cooldown));
+
+        self.realtime_watched.insert(
+            pid,
+            RealtimeWatch {
+                deadline,
+                sample: None,
+                busy: Duration::ZERO,
+            },
+        );
+    }
+
+    /// Reverts processes in their realtime cooldown to `SCHED_OTHER` if they stopped blocking.
+    ///
+    /// The CPU time of each process is compared against the time that passed since
+    /// it was last sampled. A process which kept running for nearly all of that
+    /// time, for longer than `REALTIME_BUSY` in a row, is starving the CPU. Since
+    /// both are measured per process, a delayed tick of the watchdog, such as while
+    /// the daemon scans `/proc`, cannot cause a process to be reverted.
+    pub fn realtime_watchdog(&mut self, buffer: &mut Buffer) {
+        self.realtime_reverted
+            .retain(|&pid| process::exists(buffer, pid));
+
+        if self.realtime_watched.is_empty() {
+            return;
+        }
+
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        let Some(ticks) = u64::try_from(ticks).ok().filter(|&ticks| ticks != 0) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut exited = Vec::new();
+        let mut starving = Vec::new();
+
+        for (&pid, watch) in &mut self.realtime_watched {
+            let Some(cpu_time) = process::cpu_time(buffer, pid) else {
+                exited.push(pid);
+                continue;
+            };
+
+            if let Some((sampled, previous)) = watch.sample {
+                let elapsed = now.duration_since(sampled);
+                let running =
+                    Duration::from_millis(cpu_time.saturating_sub(previous) * 1000 / ticks);
+
+                if running.as_secs_f64() >= elapsed.as_secs_f64() * REALTIME_BUSY_SHARE {
+                    watch.busy += elapsed;
+                } else {
+                    watch.busy = Duration::ZERO;
+                }
+            }
+
+            watch.sample = Some((now, cpu_time));
+
+            if watch.busy >= REALTIME_BUSY {
+                starving.push((pid, watch.busy));
+            }
+        }
+
+        for (pid, busy) in starving {
+            tracing::warn!("reverting {pid} to SCHED_OTHER: it ran for {busy:?} without blocking");
+            crate::priority::revert_realtime(buffer, pid);
+            self.realtime_reverted.insert(pid);
+        }
+
+        let reverted = &self.realtime_reverted;
+
+        self.realtime_watched.retain(|pid, watch| {
+            watch.deadline > now && !reverted.contains(pid) && !exited.contains(pid)
+        });
+    }
+
+    /// Applies a CFS profile, returning the values that were written.
+    ///
+    /// Returns `None` if CFS profiles are disabled or unsupported by the kernel.
+    pub fn cfs_apply(&self, config: &crate::config::cfs::Profile) -> Option<crate::cfs::Tweaks> {
+        let Some(paths) = &self.cfs_paths else {
+            return None;
         };
 
         if !self.config.cfs_profiles.enable {
-            return;
+            return None;
         }
 
-        crate::cfs::tweak(paths, config);
+        Some(crate::cfs::tweak(paths, config))
     }
 
     pub fn cfs_on_battery(&self, on_battery: bool) {
-        self.cfs_apply(if on_battery {
+        self.cfs_apply(self.cfs_battery_config(on_battery));
+    }
+
+    /// The CFS profile that the auto mode applies for a battery state.
+    pub fn cfs_battery_config(&self, on_battery: bool) -> &crate::config::cfs::Profile {
+        if on_battery {
             self.cfs_default_config()
         } else {
             self.cfs_responsive_config()
-        });
+        }
+    }
+
+    /// Remembers the CPU mode and profile to restore once a temporary CFS profile expires.
+    ///
+    /// If a temporary profile is already active, the state before it is kept.
+    /// Returns the generation of the revert, which only the latest timer matches.
+    pub fn cfs_revert_schedule(&mut self, cpu_mode: CpuMode, cpu_profile: &str) -> u64 {
+        self.cfs_revert_generation += 1;
+        let generation = self.cfs_revert_generation;
+
+        match self.cfs_revert {
+            Some((ref mut current, ..)) => *current = generation,
+            None => self.cfs_revert = Some((generation, cpu_mode, cpu_profile.to_owned())),
+        }
+
+        generation
+    }
+
+    /// Forgets the state to restore, as the CPU mode or profile was changed.
+    pub fn cfs_revert_cancel(&mut self) {
+        self.cfs_revert = None;
+    }
+
+    /// Takes the CPU mode and profile to restore, if the generation is the latest.
+    pub fn cfs_revert_take(&mut self, generation: u64) -> Option<(CpuMode, String)> {
+        match self.cfs_revert {
+            Some((current, ..)) if current == generation => self
+                .cfs_revert
+                .take()
+                .map(|(_, cpu_mode, cpu_profile)| (cpu_mode, cpu_profile)),
+            _ => None,
+        }
     }
 
     pub fn cfs_config(&self, name: &str) -> Option<&crate::config::cfs::Profile> {
@@ -321,6 +790,33 @@ impl<'owner> Service<'owner> {
             .unwrap_or(&crate::config::cfs::PROFILE_RESPONSIVE)
     }
 
+    /// Writes processes without an assignment to the learning mode's file.
+    pub fn learning_flush(&mut self) {
+        if !self.learned_changed {
+            return;
+        }
+
+        let Some(ref path) = self.config.process_scheduler.learning.path else {
+            return;
+        };
+
+        self.learned_changed = false;
+
+        let mut learned = self
+            .learned
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<&str>>();
+        learned.sort_unstable();
+
+        let mut contents = learned.join("\n");
+        contents.push('\n');
+
+        if let Err(why) = std::fs::write(&**path, contents) {
+            tracing::error!("failed to write unmatched processes to {path}: {why}");
+        }
+    }
+
     /// Periodically shrinks buffers and removes dead processes to keep total memory consumption low.
     pub fn garbage_clean(&mut self, buffer: &mut Buffer) {
         if self.gc_counter < 2048 {
@@ -363,6 +859,7 @@ impl<'owner> Service<'owner> {
         }
 
         self.process_map.drain_filter(&self.owner);
+        self.learning_flush();
     }
 
     /// Gets the config-assigned priority of a process.
@@ -387,8 +884,44 @@ impl<'owner> Service<'owner> {
         })
     }
 
+    // Check if the `process` belongs to a different session than the foreground process
+    //
+    // Login session scopes are compared when both processes have one. Otherwise,
+    // such as for processes of the user's service manager, the users are compared.
+    pub fn process_in_other_session(&self, process: &Process<'owner>) -> bool {
+        if let (Some(foreground), Some(current)) = (
+            self.foreground_session.as_deref(),
+            process::session(&process.cgroup),
+        ) {
+            return current != foreground;
+        }
+
+        let Some(ref user) = self.foreground_user else {
+            return false;
+        };
+
+        process::user_slice(&process.cgroup).map_or(false, |current| current != user)
+    }
+
+    // Check if the `process` is a critical system process which the configuration has not assigned
+    //
+    // The init system is protected regardless of its name. Processes in a user
+    // slice, such as `systemd --user` or a session manager, belong to the user.
+    fn process_is_protected(&self, process: &Process<'owner>) -> bool {
+        let scheduler = &self.config.process_scheduler;
+
+        scheduler.protect_system
+            && (process.id == 1 || PROTECTED_PROCESSES.contains(&process.name.as_str()))
+            && process::user_slice(&process.cgroup).is_none()
+            && scheduler.assignments.get_by_name(&process.name).is_none()
+    }
+
     // Check if the `process` is excepted from process priority changes
     pub fn process_is_exception(&self, process: &Process<'owner>) -> bool {
+        if self.process_is_protected(process) {
+            return true;
+        }
+
         // Return if listed as an exception by its cmdline path
         if self
             .config
@@ -451,7 +984,48 @@ impl<'owner> Service<'owner> {
     }
 
     pub fn process_is_pipewire_assigned(&self, process: &Process<'owner>) -> bool {
-        process.pipewire_ancestor.is_some() || self.pipewire_processes.contains(&process.id)
+        process.pipewire_ancestor.is_some() || self.pipewire_processes.contains_key(&process.id)
+    }
+
+    /// The pipewire process that a child of `parent_pid` descends from.
+    ///
+    /// The parent may not be in the process map yet, or may have already
+    /// inherited a pipewire ancestor of its own.
+    fn pipewire_ancestor_of(&self, parent_pid: u32) -> Option<u32> {
+        if self.pipewire_processes.contains_key(&parent_pid) {
+            return Some(parent_pid);
+        }
+
+        let parent = self.process_map.get_pid(parent_pid)?.ro(&self.owner);
+
+        if parent.pipewire_ancestor.is_some() {
+            return parent.pipewire_ancestor;
+        }
+
+        if self.pipewire_processes.contains_key(&parent.parent_id) {
+            return Some(parent.parent_id);
+        }
+
+        parent.ancestors(&self.owner).find_map(|process| {
+            let process = process.ro(&self.owner);
+            [process.id, process.parent_id]
+                .into_iter()
+                .find(|pid| self.pipewire_processes.contains_key(pid))
+        })
+    }
+
+    /// The pipewire profile, with its niceness biased by the node priority of a pipewire process.
+    pub fn pipewire_profile(&self, pipewire_process: u32) -> Option<Profile> {
+        let mut profile = self.config.process_scheduler.pipewire.clone()?;
+
+        if let (Some(nice), Some(&priority)) =
+            (profile.nice, self.pipewire_processes.get(&pipewire_process))
+        {
+            let bias = crate::pw::nice_bias(priority);
+            profile.nice = Some(Niceness::from(nice.get().saturating_sub(bias)));
+        }
+
+        Some(profile)
     }
 
     /// Adds a new process to the process map
@@ -462,14 +1036,136 @@ impl<'owner> Service<'owner> {
         self.process_map.insert(&mut self.owner, process)
     }
 
+    /// Resolves the assignments of all running processes without applying them.
+    ///
+    /// Returns the processes matched by each profile.
+    pub fn process_map_explain(
+        &mut self,
+        buffer: &mut Buffer,
+    ) -> BTreeMap<String, Vec<(u32, String)>> {
+        let mut report = BTreeMap::<String, Vec<(u32, String)>>::new();
+
+        let label = |profile: &Profile| {
+            if profile.observe_only {
+                [&*profile.name, " (observe-only)"].concat()
+            } else {
+                String::from(&*profile.name)
+            }
+        };
+
+        for profile in self.config.process_scheduler.assignments.profiles() {
+            report.insert(label(profile), Vec::new());
+        }
+
+        if !self.process_map_scan(buffer) {
+            return report;
+        }
+
+        // Explaining is read-only, so unmatched processes are not recorded.
+        let learning = std::mem::replace(&mut self.config.process_scheduler.learning.enable, false);
+
+        let mut process_map = process::Map::default();
+        std::mem::swap(&mut process_map, &mut self.process_map);
+
+        for process in process_map.map.values() {
+            self.assign_process_priority(buffer, process);
+
+            let process = process.ro(&self.owner);
+
+            let profile = match process.assigned_priority {
+                OwnedPriority::Config(ref profile) => label(profile),
+                OwnedPriority::Assignable => String::from("(unassigned)"),
+                OwnedPriority::Exception => String::from("(exception)"),
+                OwnedPriority::NotAssignable => continue,
+            };
+
+            report
+                .entry(profile)
+                .or_default()
+                .push((process.id, process.cmdline.clone()));
+        }
+
+        std::mem::swap(&mut process_map, &mut self.process_map);
+        self.config.process_scheduler.learning.enable = learning;
+
+        for processes in report.values_mut() {
+            processes.sort_unstable();
+        }
+
+        report
+    }
+
     /// Refreshes the process map
     pub fn process_map_refresh(&mut self, buffer: &mut Buffer) {
+        let started = std::time::Instant::now();
+        let syscalls = crate::priority::syscalls();
+        let initial = self.process_map.map.is_empty();
+
+        if !self.process_map_scan(buffer) {
+            return;
+        }
+
+        if initial {
+            self.baseline_background_start();
+        }
+
+        // Refresh priority assignments
+        let mut process_map = process::Map::default();
+        std::mem::swap(&mut process_map, &mut self.process_map);
+
+        let mut resolved = 0;
+
+        for process in process_map.map.values() {
+            if OwnedPriority::NotAssignable == process.ro(&self.owner).assigned_priority {
+                resolved += 1;
+            }
+
+            self.assign_process_priority(buffer, process);
+            self.apply_process_priority(buffer, process.ro(&self.owner));
+        }
+
+        std::mem::swap(&mut process_map, &mut self.process_map);
+
+        // Reassign foreground processes in case they were overriden.
+        if let Some(process) = self.foreground.take() {
+            self.set_foreground_process(buffer, process);
+        }
+
+        self.learning_flush();
+
+        tracing::debug!(
+            "refreshed {} processes in {:?}: {} profiles resolved, {} priority syscalls",
+            self.process_map.map.len(),
+            started.elapsed(),
+            resolved,
+            crate::priority::syscalls().wrapping_sub(syscalls)
+        );
+    }
+
+    /// Starts assignable processes at the background profile on the first refresh, if enabled.
+    ///
+    /// Until a foreground process is known, the refresh which follows applies the
+    /// background profile to them, instead of the default profile. The foreground,
+    /// pipewire, and rule assignments then promote processes from there.
+    fn baseline_background_start(&mut self) {
+        self.baseline_background = self.config.process_scheduler.baseline_background_on_start
+            && self.config.process_scheduler.foreground.is_some();
+
+        if self.baseline_background {
+            tracing::info!("applying the background profile to all assignable processes");
+        }
+    }
+
+    /// Scans `/proc` to add new processes to the map, and remove those which have exited.
+    ///
+    /// Returns `false` if `/proc` could not be read.
+    fn process_map_scan(&mut self, buffer: &mut Buffer) -> bool {
         self.process_map.drain_filter_prepare();
 
         let mut parents = BTreeMap::new();
         let Ok(procfs) = std::fs::read_dir("/proc/") else {
             tracing::error!("failed to read /proc directory: process monitoring stopped");
-            return;
+            return false;
         };
 
         for proc_entry in procfs.filter_map(Result::ok) {
@@ -488,7 +1184,16 @@ impl<'owner> Service<'owner> {
                 None => continue,
             }
 
-            process.name = process::name(&process.cmdline).to_owned();
+            process.name = process::effective_name(
+                buffer,
+                process.id,
+                process::name(&process.cmdline).to_owned(),
+                &self.config.process_scheduler.interpreters,
+            );
+
+            if self.config.process_scheduler.name_match != NameMatch::Exe {
+                process.comm = process::comm(buffer, process.id).unwrap_or_default();
+            }
 
             if let Some(cgroup) = process::cgroup(buffer, process.id) {
                 process.cgroup = cgroup.to_owned();
@@ -499,8 +1204,21 @@ impl<'owner> Service<'owner> {
                 process.parent_id = ppid;
             }
 
+            let cpu_time = if self.config.process_scheduler.manage_active_only {
+                process::cpu_time(buffer, process.id)
+            } else {
+                None
+            };
+
             self.process_map.retain_process_tree(&self.owner, &process);
-            self.process_map_insert(process);
+            let process = self.process_map_insert(process);
+
+            // Processes which did not use the CPU since the previous scan are idle.
+            if let Some(cpu_time) = cpu_time {
+                let process = process.rw(&mut self.owner);
+                process.idle = cpu_time == process.cpu_time;
+                process.cpu_time = cpu_time;
+            }
         }
 
         for (pid, ppid) in parents {
@@ -513,26 +1231,151 @@ impl<'owner> Service<'owner> {
 
         self.process_map.drain_filter(&self.owner);
 
-        // Refresh priority assignments
-        let mut process_map = process::Map::default();
-        std::mem::swap(&mut process_map, &mut self.process_map);
+        true
+    }
 
-        for process in process_map.map.values() {
-            self.assign_process_priority(process);
-            self.apply_process_priority(buffer, process.ro(&self.owner));
+    /// Reloads the configuration files.
+    ///
+    /// Diagnostics are logged by the config parser as they are found.
+    pub fn reload_configuration(&mut self) {
+        let (config, _diagnostics) = crate::config::config();
+        self.set_configuration(config);
+    }
+
+    /// Loads the configuration from a directory instead of the system paths.
+    ///
+    /// Returns the problems that were found while parsing it.
+    pub fn reload_configuration_from(
+        &mut self,
+        directory: &str,
+    ) -> Vec<crate::config::ConfigDiagnostic> {
+        let (config, diagnostics) = crate::config::config_from(directory);
+        self.set_configuration(config);
+        diagnostics
+    }
+
+    fn set_configuration(&mut self, config: crate::config::Config) {
+        self.config = config;
+        self.learned.clear();
+        self.learned_changed = false;
+    }
+
+    /// Sets the foreground process of a source, and applies the effective foreground process.
+    ///
+    /// When multiple sources have set a foreground process, the source with the
+    /// highest priority wins. A pid of `0` unregisters the source.
+    pub fn set_foreground_source(
+        &mut self,
+        buffer: &mut Buffer,
+        source: ForegroundSource,
+        pid: u32,
+        fullscreen: bool,
+    ) {
+        if pid == 0 {
+            self.foreground_sources.remove(&source);
+        } else {
+            self.foreground_sources.insert(source, (pid, fullscreen));
         }
 
-        std::mem::swap(&mut process_map, &mut self.process_map);
+        // Sources whose foreground process has exited no longer take precedence.
+        self.foreground_sources
+            .retain(|_, (pid, _)| process::exists(buffer, *pid));
 
-        // Reassign foreground processes in case they were overriden.
-        if let Some(process) = self.foreground.take() {
-            self.set_foreground_process(buffer, process);
+        let foreground = self.foreground_sources.values().next_back().copied();
+
+        self.set_foreground_fullscreen(foreground.map_or(false, |(_, fullscreen)| fullscreen));
+
+        if let Some((pid, _)) = foreground {
+            self.set_foreground_process(buffer, pid);
         }
     }
 
-    /// Reloads the configuration files.
-    pub fn reload_configuration(&mut self) {
-        self.config = crate::config::config();
+    /// Tracks whether the foreground process is fullscreen.
+    fn set_foreground_fullscreen(&mut self, fullscreen: bool) {
+        if self.foreground_fullscreen == fullscreen {
+            return;
+        }
+
+        self.foreground_fullscreen = fullscreen;
+
+        if self.config.process_scheduler.quiet_on_fullscreen {
+            if fullscreen {
+                tracing::info!("pausing process refreshes while the foreground is fullscreen");
+            } else {
+                tracing::info!("resuming process refreshes");
+            }
+        }
+    }
+
+    /// Process refreshes are paused while the foreground process is fullscreen,
+    /// if `quiet-on-fullscreen` is enabled.
+    pub fn refresh_paused(&self) -> bool {
+        self.foreground_fullscreen && self.config.process_scheduler.quiet_on_fullscreen
+    }
+
+    /// The profile of foreground processes, which game mode may replace.
+    fn foreground_profile<'a>(&'a self, assignments: &'a ForegroundAssignments) -> &'a Profile {
+        match (&self.game_mode, &self.config.game_mode.foreground) {
+            (Some(_), Some(profile)) => profile,
+            _ => &assignments.foreground,
+        }
+    }
+
+    /// Whether game mode is enabled.
+    pub fn game_mode_active(&self) -> bool {
+        self.game_mode.is_some()
+    }
+
+    /// Enables game mode, remembering the CPU mode and profile to restore when it is disabled.
+    pub fn game_mode_enable(
+        &mut self,
+        buffer: &mut Buffer,
+        cpu_mode: CpuMode,
+        cpu_profile: String,
+    ) {
+        self.game_mode = Some((cpu_mode, cpu_profile));
+        self.game_mode_reapply(buffer);
+    }
+
+    /// Disables game mode, returning the CPU mode and profile to restore.
+    pub fn game_mode_disable(&mut self, buffer: &mut Buffer) -> Option<(CpuMode, String)> {
+        let previous = self.game_mode.take();
+        self.game_mode_reapply(buffer);
+        previous
+    }
+
+    /// Reapplies the foreground profile and CPU pinning after game mode was toggled.
+    fn game_mode_reapply(&mut self, buffer: &mut Buffer) {
+        if let Some(pid) = self.foreground {
+            self.set_foreground_process(buffer, pid);
+        } else {
+            self.game_mode_pin(buffer);
+        }
+    }
+
+    /// Pins the foreground processes to the CPUs of game mode, and unpins those
+    /// which are no longer in the foreground.
+    fn game_mode_pin(&mut self, buffer: &mut Buffer) {
+        let cpus: &[usize] = if self.game_mode.is_some() {
+            &self.config.game_mode.cpus
+        } else {
+            &[]
+        };
+
+        for &pid in &self.game_pinned {
+            if cpus.is_empty() || !self.foreground_processes.contains(&pid) {
+                crate::priority::set_affinity(buffer, pid, &[]);
+            }
+        }
+
+        self.game_pinned.clear();
+
+        if !cpus.is_empty() {
+            for &pid in &self.foreground_processes {
+                crate::priority::set_affinity(buffer, pid, cpus);
+                self.game_pinned.push(pid);
+            }
+        }
     }
 
     /// Sets a process as the foreground.
@@ -544,11 +1387,37 @@ impl<'owner> Service<'owner> {
             self.foreground_processes.clear();
             self.foreground_processes.push(pid);
 
+            // Processes of other sessions on the same machine are left as they are.
+            let cgroup = match self.process_map.get_pid(pid) {
+                Some(process) if !process.ro(&self.owner).cgroup.is_empty() => {
+                    process.ro(&self.owner).cgroup.clone()
+                }
+                _ => process::cgroup(buffer, pid)
+                    .map(String::from)
+                    .unwrap_or_default(),
+            };
+
+            self.foreground_session = process::session(&cgroup).map(String::from);
+            self.foreground_user = process::user_slice(&cgroup).map(String::from);
+
+            // Focusing any member of a launcher group focuses the whole group.
+            let root = self
+                .process_map
+                .get_pid(pid)
+                .and_then(|process| process.ro(&self.owner).launcher_group.as_ref())
+                .map_or(pid, |&(root, _)| root);
+
             for process in self.process_map.map.values() {
                 let process = process.ro(&self.owner);
 
+                if self.process_in_other_session(process) {
+                    continue;
+                }
+
                 if let Priority::Assignable = self.process_assignment(process.id) {
-                    let profile = if process.id == pid || self.process_descended_from(process, pid)
+                    let profile = if process.id == pid
+                        || process.id == root
+                        || self.process_descended_from(process, root)
                     {
                         self.foreground_processes.push(process.id);
 
@@ -556,25 +1425,40 @@ impl<'owner> Service<'owner> {
                             continue;
                         }
 
-                        &assignments.foreground
+                        self.foreground_profile(assignments)
                     } else if self.process_is_pipewire_assigned(process) {
                         continue;
+                    } else if let Some((_, profile)) = process.launcher_group.as_ref() {
+                        profile
                     } else {
                         &assignments.background
                     };
 
-                    crate::priority::set(buffer, process.id, profile);
+                    crate::priority::set(
+                        buffer,
+                        process.id,
+                        profile,
+                        self.config.process_scheduler.manage,
+                    );
                 }
             }
         }
+
+        if self.game_mode.is_some() || !self.game_pinned.is_empty() {
+            self.game_mode_pin(buffer);
+        }
     }
 
     /// Assigns a process to the pipewire profile if it does not already have an assignment.
-    pub fn set_pipewire_process(&mut self, buffer: &mut Buffer, process: u32) {
+    pub fn set_pipewire_process(&mut self, buffer: &mut Buffer, node: scheduler_pipewire::Process) {
+        let process = node.id;
+
         self.assign_children(buffer, process);
 
-        if let Some(pipewire) = self.config.process_scheduler.pipewire.clone() {
-            if !self.pipewire_processes.contains(&process) {
+        if self.config.process_scheduler.pipewire.is_some() {
+            let added = !self.pipewire_processes.contains_key(&process);
+
+            if added {
                 if let Some(process) = self.process_map.get_pid(process) {
                     let process = process.ro(&self.owner);
                     if OwnedPriority::Assignable != process.assigned_priority {
@@ -582,22 +1466,41 @@ impl<'owner> Service<'owner> {
                     }
                 }
 
-                self.pipewire_processes.push(process);
+                self.pipewire_processes
+                    .insert(process, node.priority.unwrap_or(0));
             }
 
+            let Some(pipewire) = self.pipewire_profile(process) else {
+                return;
+            };
+
             for current_cell in self.process_map.map.values() {
                 let current = current_cell.ro(&self.owner);
                 let pid = current.id;
 
                 if let Priority::Assignable = self.process_assignment(current.id) {
                     if pid == process {
-                        crate::priority::set(buffer, process, &pipewire);
+                        crate::priority::set(
+                            buffer,
+                            process,
+                            &self.realtime_guard(pid, &pipewire),
+                            self.config.process_scheduler.manage,
+                        );
                     } else if self.process_descended_from(current, process) {
                         current_cell.rw(&mut self.owner).pipewire_ancestor = Some(process);
-                        crate::priority::set(buffer, pid, &pipewire);
+                        crate::priority::set(
+                            buffer,
+                            pid,
+                            &self.realtime_guard(pid, &pipewire),
+                            self.config.process_scheduler.manage,
+                        );
                     }
                 }
             }
+
+            if added && pipewire.sched_policy.is_realtime() {
+                self.realtime_watch(process);
+            }
         }
     }
 
@@ -605,11 +1508,9 @@ impl<'owner> Service<'owner> {
     ///
     /// Assigns the background or foreground process priority, if that feature is enabled.
     pub fn remove_pipewire_process(&mut self, buffer: &mut Buffer, process_id: u32) {
-        let Some(index) = self.pipewire_processes.iter().position(|pid| *pid == process_id) else {
+        if self.pipewire_processes.remove(&process_id).is_none() {
             return;
-        };
-
-        self.pipewire_processes.remove(index);
+        }
 
         for process_cell in self.process_map.map.values() {
             let process = process_cell.rw(&mut self.owner);
@@ -621,12 +1522,19 @@ impl<'owner> Service<'owner> {
                 if let Some(ref assignments) = self.config.process_scheduler.foreground {
                     if let Priority::Assignable = self.process_assignment(process.id) {
                         let profile = if self.foreground_processes.contains(&process.id) {
-                            &assignments.foreground
+                            self.foreground_profile(assignments)
+                        } else if let Some((_, profile)) = process.launcher_group.as_ref() {
+                            profile
                         } else {
                             &assignments.background
                         };
 
-                        crate::priority::set(buffer, process.id, profile);
+                        crate::priority::set(
+                            buffer,
+                            process.id,
+                            profile,
+                            self.config.process_scheduler.manage,
+                        );
                     }
                 }
             }
@@ -634,6 +1542,22 @@ impl<'owner> Service<'owner> {
     }
 }
 
+/// How long a process in its realtime cooldown may run without blocking before it is reverted.
+const REALTIME_BUSY: Duration = Duration::from_secs(3);
+
+/// Share of the elapsed time that a process must have run for to be considered busy.
+const REALTIME_BUSY_SHARE: f64 = 0.9;
+
+/// A process in its realtime cooldown.
+struct RealtimeWatch {
+    /// When the cooldown ends.
+    deadline: Instant,
+    /// When the process was last sampled, and its CPU time in clock ticks at the time.
+    sample: Option<(Instant, u64)>,
+    /// How long the process has been running without blocking.
+    busy: Duration,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Priority<'a> {
     Assignable,
@@ -661,3 +1585,29 @@ impl OwnedPriority {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Service;
+    use crate::utils::Buffer;
+
+    #[test]
+    fn exited_process_is_not_recorded() {
+        qcell::LCellOwner::scope(|owner| {
+            let mut service = Service::new(owner);
+            let mut buffer = Buffer::new();
+
+            let pid = u32::MAX;
+            let recorded = service.assign_new_process(
+                &mut buffer,
+                pid,
+                1,
+                "gone".into(),
+                "/usr/bin/gone".into(),
+            );
+
+            assert!(!recorded);
+            assert!(service.process_map.get_pid(pid).is_none());
+        });
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2023 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+//! Tracks the focused window on wlroots-based compositors through the
+//! `wlr-foreign-toplevel-management` protocol, and sets its process as the
+//! foreground process of the daemon.
+
+use crate::{process, utils::Buffer};
+use std::{
+    collections::HashMap,
+    os::unix::{fs::MetadataExt, prelude::OsStrExt},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use wayland_client::{
+    backend::ObjectId,
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::wl_registry,
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+
+pub async fn main(connection: zbus::Connection) -> anyhow::Result<()> {
+    let mut client = crate::dbus::ClientProxy::new(&connection).await?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    std::thread::spawn(move || {
+        if let Err(why) = watch_activated(&tx) {
+            tracing::error!("failed to monitor wayland toplevels: {why}");
+        }
+    });
+
+    let mut buffer = Buffer::new();
+    let mut foreground = None;
+
+    while let Some((app_id, fullscreen)) = rx.recv().await {
+        let Some(pid) = pid_from_app_id(&mut buffer, &app_id) else {
+            tracing::debug!("no process found for {app_id}");
+            continue;
+        };
+
+        if foreground == Some((pid, fullscreen)) {
+            continue;
+        }
+
+        foreground = Some((pid, fullscreen));
+
+        tracing::debug!("setting {app_id} ({pid}) as foreground process");
+        client
+            .set_foreground_process_fullscreen("compositor", pid, fullscreen)
+            .await?;
+    }
+
+    anyhow::bail!("wayland toplevel monitor exited")
+}
+
+/// Sends the app ID of each toplevel that becomes activated, and whether it is fullscreen.
+fn watch_activated(tx: &UnboundedSender<(String, bool)>) -> anyhow::Result<()> {
+    let connection = Connection::connect_to_env()?;
+    let (globals, mut queue) = registry_queue_init::<State>(&connection)?;
+
+    let _manager: ZwlrForeignToplevelManagerV1 = globals.bind(&queue.handle(), 1..=3, ())?;
+
+    let mut state = State::default();
+
+    loop {
+        queue.blocking_dispatch(&mut state)?;
+
+        if let Some(activated) = state.activated.take() {
+            if tx.send(activated).is_err() {
+                return Ok(());
+            }
+        }
+
+        if state.finished {
+            anyhow::bail!("compositor stopped sending toplevel events");
+        }
+    }
+}
+
+/// Finds the process of an app ID which is owned by the current user.
+///
+/// The protocol does not provide process IDs, so the app ID is matched against
+/// process names, as well as the last component of a reverse-DNS app ID such as
+/// `org.gnome.Nautilus`. The matching process whose parent does not also match
+/// is chosen, so that the whole process tree of the app is considered foreground.
+fn pid_from_app_id(buffer: &mut Buffer, app_id: &str) -> Option<u32> {
+    let short_id = app_id.rsplit('.').next().unwrap_or(app_id);
+    let uid = unsafe { libc::getuid() };
+
+    let mut matches = Vec::new();
+
+    for entry in std::fs::read_dir("/proc/").ok()?.filter_map(Result::ok) {
+        let Some(pid) = atoi::atoi::<u32>(entry.file_name().as_bytes()) else {
+            continue;
+        };
+
+        if entry.metadata().map_or(true, |meta| meta.uid() != uid) {
+            continue;
+        }
+
+        let Some(cmdline) = process::cmdline(buffer, pid) else {
+            continue;
+        };
+
+        let name = process::name(&cmdline);
+
+        if name.eq_ignore_ascii_case(app_id) || name.eq_ignore_ascii_case(short_id) {
+            matches.push(pid);
+        }
+    }
+
+    matches
+        .iter()
+        .copied()
+        .find(|&pid| process::parent_id(buffer, pid).map_or(true, |ppid| !matches.contains(&ppid)))
+}
+
+#[derive(Default)]
+struct State {
+    toplevels: HashMap<ObjectId, Toplevel>,
+    activated: Option<(String, bool)>,
+    finished: bool,
+}
+
+#[derive(Default)]
+struct Toplevel {
+    app_id: String,
+    activated: bool,
+    fullscreen: bool,
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+        _state: &mut Self,
+        _registry: &wl_registry::WlRegistry,
+        _event: wl_registry::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _manager: &ZwlrForeignToplevelManagerV1,
+        event: zwlr_foreign_toplevel_manager_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } => {
+                state.toplevels.insert(toplevel.id(), Toplevel::default());
+            }
+
+            zwlr_foreign_toplevel_manager_v1::Event::Finished => state.finished = true,
+
+            _ => (),
+        }
+    }
+
+    wayland_client::event_created_child!(State, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        handle: &ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        use zwlr_foreign_toplevel_handle_v1::Event;
+
+        let toplevel = state.toplevels.entry(handle.id()).or_default();
+
+        match event {
+            Event::AppId { app_id } => toplevel.app_id = app_id,
+
+            Event::State { state: flags } => {
+                const ACTIVATED: u32 = zwlr_foreign_toplevel_handle_v1::State::Activated as u32;
+                const FULLSCREEN: u32 = zwlr_foreign_toplevel_handle_v1::State::Fullscreen as u32;
+
+                toplevel.activated = false;
+                toplevel.fullscreen = false;
+
+                for flag in flags.chunks_exact(4) {
+                    match u32::from_ne_bytes([flag[0], flag[1], flag[2], flag[3]]) {
+                        ACTIVATED => toplevel.activated = true,
+                        FULLSCREEN => toplevel.fullscreen = true,
+                        _ => (),
+                    }
+                }
+            }
+
+            // Marks the end of an atomic set of changes to the toplevel.
+            Event::Done => {
+                if toplevel.activated && !toplevel.app_id.is_empty() {
+                    state.activated = Some((toplevel.app_id.clone(), toplevel.fullscreen));
+                }
+            }
+
+            Event::Closed => {
+                state.toplevels.remove(&handle.id());
+                handle.destroy();
+            }
+
+            _ => (),
+        }
+    }
+}
@@ -34,7 +34,7 @@ pub enum NodeEvent<'a> {
 #[derive(Debug)]
 pub enum ProcessEvent {
     /// Process add
-    Add(u32),
+    Add(Process),
     /// Process remove
     Remove(u32),
 }
@@ -49,7 +49,10 @@ impl ProcessEvent {
         let pid = atoi::atoi::<u32>(fields.next()?)?;
 
         match method {
-            b"add" => Some(ProcessEvent::Add(pid)),
+            b"add" => Some(ProcessEvent::Add(Process {
+                id: pid,
+                priority: fields.next().and_then(atoi::atoi::<i32>),
+            })),
             b"rem" => Some(ProcessEvent::Remove(pid)),
             _ => None,
         }
@@ -59,14 +62,21 @@ impl ProcessEvent {
     ///
     /// - Failure to write bytes to writer
     pub fn to_bytes<W: std::io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        let (method, pid) = match self {
-            ProcessEvent::Add(pid) => (b"add", *pid),
-            ProcessEvent::Remove(pid) => (b"rem", *pid),
+        let (method, pid, priority) = match self {
+            ProcessEvent::Add(process) => (b"add", process.id, process.priority),
+            ProcessEvent::Remove(pid) => (b"rem", *pid, None),
         };
 
         writer.write_all(method)?;
         writer.write_all(b" ")?;
-        writer.write_all(itoa::Buffer::new().format(pid).as_bytes())
+        writer.write_all(itoa::Buffer::new().format(pid).as_bytes())?;
+
+        if let Some(priority) = priority {
+            writer.write_all(b" ")?;
+            writer.write_all(itoa::Buffer::new().format(priority).as_bytes())?;
+        }
+
+        Ok(())
     }
 }
 
@@ -76,6 +86,10 @@ impl ProcessEvent {
 pub struct Process {
     /// Process ID
     pub id: u32,
+    /// Priority of the node, from `priority.driver` or `priority.session`
+    ///
+    /// Nodes with higher priorities are preferred as the driver of the graph.
+    pub priority: Option<i32>,
 }
 
 impl Process {
@@ -85,8 +99,14 @@ impl Process {
         let props = info.props()?;
         props.get("application.process.binary")?;
 
+        let priority = props
+            .get("priority.driver")
+            .or_else(|| props.get("priority.session"))
+            .and_then(|priority| priority.parse::<i32>().ok());
+
         Some(Process {
             id: props.get("application.process.id")?.parse::<u32>().ok()?,
+            priority,
         })
     }
 }
@@ -101,7 +121,7 @@ pub fn processes_from_socket(socket: &OwnedFd, mut func: impl FnMut(ProcessEvent
         NodeEvent::Info(pw_id, info) => {
             if let Some(process) = Process::from_node(info) {
                 if managed.insert(pw_id, process.id).is_none() {
-                    func(ProcessEvent::Add(process.id));
+                    func(ProcessEvent::Add(process));
                 }
             }
         }